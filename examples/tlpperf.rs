@@ -1,14 +1,16 @@
 #![warn(rust_2018_idioms)]
 
-use libtlp::{pci, DmaDirection, NetTlp};
+use libtlp::{pci, DmaDirection, DmaReadRequest, Error, NetTlp};
 
 use std::net::Ipv4Addr;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 
 use anyhow::{bail, Result};
 use clap::Parser;
+use hdrhistogram::Histogram;
 
 static RUNNING: AtomicBool = AtomicBool::new(true);
 
@@ -26,7 +28,7 @@ struct Args {
     #[clap(short, long = "remote")]
     remote_addr: Ipv4Addr,
 
-    /// TLP tag
+    /// First TLP tag. Each thread uses `max(batch, 1)` tags from it.
     #[clap(short, long, default_value_t = 0)]
     tag: u8,
 
@@ -45,18 +47,36 @@ struct Args {
     #[clap(long, default_value_t = 256)]
     dma_len: usize,
 
-    /// DMA Pattern
+    /// DMA Pattern: "seq", "seq512", "stride:<bytes>", "reverse", "fix", "random[:<align>]",
+    /// "zipf[:<theta>]", "cross" (page crossing) or "trace:<file>"
     #[clap(long, default_value = "seq")]
     pattern: DmaPattern,
 
+    /// Benchmark mode: "read", "write" or "mixed:<percentage of reads>"
+    #[clap(long, default_value = "read")]
+    mode: BenchMode,
+
+    /// Payload of DMA write: "zero", "ones", "incr" or "random"
+    #[clap(long, default_value = "incr")]
+    payload: Payload,
+
     /// MaxReadRequestSize (MRRS)
     #[clap(short, long, default_value_t = 512)]
     mrrs: usize,
 
+    /// Number of read requests issued at once, each with its own tag
+    /// (see `NetTlp::dma_read_batch`)
+    #[clap(long, default_value_t = 1)]
+    batch: usize,
+
     /// Measure latency
     #[clap(long)]
     latency: bool,
 
+    /// Write the latency distribution to a file (JSON if the extension is .json, CSV otherwise)
+    #[clap(long, requires = "latency")]
+    latency_output: Option<std::path::PathBuf>,
+
     /// Number of threads
     #[clap(long, default_value_t = 1)]
     nthreads: u8,
@@ -69,55 +89,640 @@ struct Args {
     #[clap(long, default_value_t = 0)]
     interval: u64,
 
+    /// Open-loop mode: issue requests at this rate (transactions/s in total, e.g., 100k)
+    #[clap(long, parse(try_from_str = parse_rate), conflicts_with_all = &["interval", "bandwidth"])]
+    rate: Option<f64>,
+
+    /// Open-loop mode: issue requests at this bandwidth (bits/s in total, e.g., 10G)
+    #[clap(long, parse(try_from_str = parse_rate), conflicts_with = "interval")]
+    bandwidth: Option<f64>,
+
+    /// Number of transactions (--rate) or bits (--bandwidth) that can be issued at once
+    /// after the load generator falls behind
+    #[clap(long)]
+    burst: Option<f64>,
+
     /// Benchmark duration
     #[clap(long, default_value_t = 0)]
     duration: u32,
 
+    /// Number of times a failed request is retried
+    #[clap(long, default_value_t = 0)]
+    retries: u32,
+
+    /// Keep running when a request fails (after retries) instead of stopping the benchmark
+    #[clap(long)]
+    keep_going: bool,
+
+    /// Output format: "text", "json" (JSON Lines) or "csv".
+    /// The configuration is included in the JSON summary record only (see --config-output)
+    #[clap(long, default_value = "text")]
+    format: Format,
+
+    /// Write the configuration to a file in JSON
+    #[clap(long)]
+    config_output: Option<std::path::PathBuf>,
+
     /// Debug mode
     #[clap(short, long)]
     debug: bool,
 }
 
+impl Args {
+    // Pacer of a thread for the open-loop mode
+    fn pacer(&self) -> Option<(Pacer, bool)> {
+        let nthreads = self.nthreads as f64;
+        let (rate, bits) = match (self.rate, self.bandwidth) {
+            (Some(rate), _) => (rate / nthreads, false),
+            (_, Some(bandwidth)) => (bandwidth / nthreads, true),
+            _ => return None,
+        };
+        // One iteration at once by default
+        let per_iter =
+            self.batch.max(1) as f64 * if bits { self.dma_len as f64 * 8.0 } else { 1.0 };
+        let burst = self.burst.map_or(per_iter, |b| b / nthreads).max(per_iter);
+        Some((Pacer::new(rate, burst), bits))
+    }
+
+    fn config(&self) -> serde_json::Value {
+        serde_json::json!({
+            "bdf": self.bdf.to_string(),
+            "tag": self.tag,
+            "local": self.local_addr.to_string(),
+            "remote": self.remote_addr.to_string(),
+            "region_addr": self.region_addr,
+            "region_size": self.region_size,
+            "dma_len": self.dma_len,
+            "pattern": self.pattern.to_string(),
+            "mode": format!("{:?}", self.mode),
+            "payload": format!("{:?}", self.payload),
+            "mrrs": self.mrrs,
+            "batch": self.batch,
+            "latency": self.latency,
+            "latency_output": self.latency_output,
+            "nthreads": self.nthreads,
+            "count": self.count,
+            "interval": self.interval,
+            "rate": self.rate,
+            "bandwidth": self.bandwidth,
+            "burst": self.burst,
+            "duration": self.duration,
+            "retries": self.retries,
+            "keep_going": self.keep_going,
+            "format": format!("{:?}", self.format),
+            "debug": self.debug,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum BenchMode {
+    Read,
+    Write,
+    /// Percentage of reads
+    Mixed(u8),
+}
+
+impl BenchMode {
+    fn has_write(&self) -> bool {
+        !matches!(self, BenchMode::Read)
+    }
+
+    // Decide whether the next request is a read
+    fn next_is_read(&self) -> bool {
+        use rand::Rng;
+        match self {
+            BenchMode::Read => true,
+            BenchMode::Write => false,
+            BenchMode::Mixed(pct) => rand::thread_rng().gen_range(0..100) < *pct,
+        }
+    }
+}
+
+impl std::str::FromStr for BenchMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mode = match s {
+            "read" => BenchMode::Read,
+            "write" => BenchMode::Write,
+            _ => match s.strip_prefix("mixed:").map(str::parse::<u8>) {
+                Some(Ok(pct)) if pct <= 100 => BenchMode::Mixed(pct),
+                _ => bail!("Invalid mode: {}", s),
+            },
+        };
+        Ok(mode)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Payload {
+    Zero,
+    Ones,
+    Incr,
+    Random,
+}
+
+impl Payload {
+    fn fill(&self, buf: &mut [u8]) {
+        match self {
+            Payload::Zero => buf.fill(0),
+            Payload::Ones => buf.fill(0xFF),
+            Payload::Incr => buf.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8),
+            Payload::Random => rand::Rng::fill(&mut rand::thread_rng(), buf),
+        }
+    }
+}
+
+impl std::str::FromStr for Payload {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let payload = match s {
+            "zero" => Payload::Zero,
+            "ones" => Payload::Ones,
+            "incr" => Payload::Incr,
+            "random" => Payload::Random,
+            _ => bail!("Invalid payload: {}", s),
+        };
+        Ok(payload)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let format = match s {
+            "text" => Format::Text,
+            "json" => Format::Json,
+            "csv" => Format::Csv,
+            _ => bail!("Invalid format: {}", s),
+        };
+        Ok(format)
+    }
+}
+
+// Parse a number with an optional k, M or G suffix
+fn parse_rate(s: &str) -> Result<f64> {
+    let (num, unit) = match s.strip_suffix(['k', 'K']) {
+        Some(num) => (num, 1e3),
+        None => match s.strip_suffix('M') {
+            Some(num) => (num, 1e6),
+            None => match s.strip_suffix('G') {
+                Some(num) => (num, 1e9),
+                None => (s, 1.0),
+            },
+        },
+    };
+    let rate = num.parse::<f64>()? * unit;
+    if !(rate > 0.0 && rate.is_finite()) {
+        bail!("rate must be positive: {}", s);
+    }
+    Ok(rate)
+}
+
+/// Token bucket that paces requests for the open-loop mode
+///
+/// Each request is assigned a due time according to the rate.
+/// Latency is measured from the due time rather than the actual send time,
+/// so that it includes the time requests would have waited in a queue
+/// when the adapter cannot keep up with the offered load.
+#[derive(Debug)]
+struct Pacer {
+    /// Nanoseconds per token
+    ns_per_token: f64,
+    /// Maximum number of tokens that can be accumulated
+    burst: f64,
+    next: Instant,
+}
+
+impl Pacer {
+    fn new(rate: f64, burst: f64) -> Self {
+        Pacer {
+            ns_per_token: 1e9 / rate,
+            burst,
+            next: Instant::now(),
+        }
+    }
+
+    // Wait until `tokens` are available and return the due time of the request
+    fn acquire(&mut self, tokens: f64) -> Instant {
+        let now = Instant::now();
+        // Tokens accumulated while idle are capped at `burst`
+        let cap = std::time::Duration::from_nanos((self.burst * self.ns_per_token) as u64);
+        if let Some(earliest) = now.checked_sub(cap) {
+            self.next = self.next.max(earliest);
+        }
+        let due = self.next;
+        self.next += std::time::Duration::from_nanos((tokens * self.ns_per_token) as u64);
+
+        // Sleep is not precise enough; spin for the last part
+        const SPIN: std::time::Duration = std::time::Duration::from_micros(100);
+        let wait = due.saturating_duration_since(now);
+        if wait > SPIN {
+            std::thread::sleep(wait - SPIN);
+        }
+        while Instant::now() < due {
+            std::hint::spin_loop();
+        }
+        due
+    }
+}
+
+/// Region accessed by a thread
 #[derive(Copy, Clone, Debug)]
-enum DmaPattern {
-    SEQ,
-    SEQ512,
-    FIX,
-    RANDOM,
+struct Region {
+    start: u64,
+    size: u64,
+    /// DMA length for one request
+    len: u64,
+    /// Alignment of every address (4 for DMA write, which must be DW-aligned)
+    align: u64,
+}
+
+impl Region {
+    /// Number of `len`-byte slots in the region, for the patterns that slot the region
+    fn slots(&self) -> Result<u64> {
+        if self.len == 0 || self.len > self.size {
+            bail!(
+                "dma_len must be between 1 and the region size {} to slot the region: {}",
+                self.size,
+                self.len
+            );
+        }
+        Ok(self.size / self.len)
+    }
+}
+
+/// A sequence of request addresses
+///
+/// To add a pattern, implement this trait and add its constructor to `PATTERNS`.
+trait AccessPattern: std::fmt::Debug + Send {
+    /// Address of the next request
+    fn next_addr(&mut self) -> u64;
+}
+
+/// Constructor of a pattern from the region and the argument of `--pattern name:arg`
+type PatternCtor = fn(Region, Option<&str>) -> Result<Box<dyn AccessPattern>>;
+
+/// Name, usage and constructor of the patterns
+const PATTERNS: &[(&str, &str, PatternCtor)] = &[
+    ("seq", "sequential", Stride::seq),
+    ("seq512", "sequential with 512-byte stride", Stride::seq512),
+    (
+        "stride",
+        "stride:<bytes>, sequential with a stride",
+        Stride::build,
+    ),
+    (
+        "reverse",
+        "sequential from the end of the region",
+        Reverse::build,
+    ),
+    ("fix", "the start of the region", Fix::build),
+    (
+        "random",
+        "random[:<align>], uniformly random (DW-aligned by default)",
+        Random::build,
+    ),
+    (
+        "zipf",
+        "zipf[:<theta>], zipfian over len-byte slots (theta 0.99 by default)",
+        Zipf::build,
+    ),
+    (
+        "cross",
+        "every request crosses a 4 KiB boundary",
+        PageCross::build,
+    ),
+    (
+        "trace",
+        "trace:<file>, addresses in a file (one per line) repeatedly",
+        Trace::build,
+    ),
+];
+
+/// `--pattern`, a pattern name and an optional argument
+#[derive(Clone, Debug)]
+struct DmaPattern {
+    name: String,
+    arg: Option<String>,
+}
+
+impl DmaPattern {
+    fn build(&self, region: Region) -> Result<Box<dyn AccessPattern>> {
+        if region.size < region.len {
+            bail!("region size {} is smaller than dma_len", region.size);
+        }
+        let (_, _, ctor) = PATTERNS.iter().find(|p| p.0 == self.name).unwrap();
+        ctor(region, self.arg.as_deref())
+    }
+
+    fn usage() -> String {
+        PATTERNS
+            .iter()
+            .map(|(name, usage, _)| format!("  {}: {}", name, usage))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl std::fmt::Display for DmaPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(arg) = &self.arg {
+            write!(f, ":{}", arg)?;
+        }
+        Ok(())
+    }
 }
 
 impl std::str::FromStr for DmaPattern {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let pat = match s {
-            "seq" => DmaPattern::SEQ,
-            "seq512" => DmaPattern::SEQ512,
-            "fix" => DmaPattern::FIX,
-            "random" => DmaPattern::RANDOM,
-            _ => bail!("Invalid pattern: {}", s),
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg.to_string())),
+            None => (s, None),
         };
-        Ok(pat)
+        if !PATTERNS.iter().any(|p| p.0 == name) {
+            bail!("Invalid pattern: {}\nPatterns:\n{}", s, DmaPattern::usage());
+        }
+        Ok(DmaPattern {
+            name: name.to_string(),
+            arg,
+        })
     }
 }
 
-fn next_addr(start: u64, size: u64, addr: u64, len: u64, pat: DmaPattern) -> u64 {
-    match pat {
-        DmaPattern::SEQ => {
-            if (addr + len) > (start + size) {
-                start
-            } else {
-                addr + len
-            }
+fn parse_arg<T: std::str::FromStr>(name: &str, arg: Option<&str>) -> Result<Option<T>> {
+    arg.map(|a| a.parse())
+        .transpose()
+        .map_err(|_| anyhow::anyhow!("Invalid argument of {}: {}", name, arg.unwrap()))
+}
+
+/// Sequential access with a stride, wrapping around at the end of the region
+#[derive(Debug)]
+struct Stride {
+    region: Region,
+    stride: u64,
+    next: u64,
+}
+
+impl Stride {
+    fn with_stride(region: Region, stride: u64) -> Result<Box<dyn AccessPattern>> {
+        if stride == 0 {
+            bail!("stride must not be zero");
         }
-        DmaPattern::SEQ512 => {
-            if (addr + 512) > (start + size) {
-                start
-            } else {
-                addr + 512
-            }
+        Ok(Box::new(Stride {
+            region,
+            stride,
+            next: region.start,
+        }))
+    }
+
+    fn seq(region: Region, _: Option<&str>) -> Result<Box<dyn AccessPattern>> {
+        Stride::with_stride(region, region.len)
+    }
+
+    fn seq512(region: Region, _: Option<&str>) -> Result<Box<dyn AccessPattern>> {
+        Stride::with_stride(region, 512)
+    }
+
+    fn build(region: Region, arg: Option<&str>) -> Result<Box<dyn AccessPattern>> {
+        let stride = parse_arg::<u64>("stride", arg)?
+            .ok_or_else(|| anyhow::anyhow!("stride requires the stride, e.g., stride:4096"))?;
+        if !stride.is_multiple_of(region.align) {
+            bail!("stride must be a multiple of {}: {}", region.align, stride);
+        }
+        Stride::with_stride(region, stride)
+    }
+}
+
+impl AccessPattern for Stride {
+    fn next_addr(&mut self) -> u64 {
+        let addr = self.next;
+        self.next += self.stride;
+        if self.next + self.region.len > self.region.start + self.region.size {
+            self.next = self.region.start;
+        }
+        addr
+    }
+}
+
+/// Sequential access from the last slot to the first one
+#[derive(Debug)]
+struct Reverse {
+    region: Region,
+    slots: u64,
+    slot: u64,
+}
+
+impl Reverse {
+    fn build(region: Region, _: Option<&str>) -> Result<Box<dyn AccessPattern>> {
+        let slots = region.slots()?;
+        Ok(Box::new(Reverse {
+            region,
+            slots,
+            slot: slots - 1,
+        }))
+    }
+}
+
+impl AccessPattern for Reverse {
+    fn next_addr(&mut self) -> u64 {
+        let addr = self.region.start + self.slot * self.region.len;
+        self.slot = self.slot.checked_sub(1).unwrap_or(self.slots - 1);
+        addr
+    }
+}
+
+/// The same address
+#[derive(Debug)]
+struct Fix(u64);
+
+impl Fix {
+    fn build(region: Region, _: Option<&str>) -> Result<Box<dyn AccessPattern>> {
+        Ok(Box::new(Fix(region.start)))
+    }
+}
+
+impl AccessPattern for Fix {
+    fn next_addr(&mut self) -> u64 {
+        self.0
+    }
+}
+
+/// Uniformly random addresses aligned to `align`
+#[derive(Debug)]
+struct Random {
+    /// The first and the last address
+    first: u64,
+    last: u64,
+    align: u64,
+}
+
+impl Random {
+    fn build(region: Region, arg: Option<&str>) -> Result<Box<dyn AccessPattern>> {
+        let align = parse_arg::<u64>("random", arg)?.unwrap_or(4);
+        if !align.is_power_of_two() {
+            bail!("alignment must be a power of two: {}", align);
+        }
+        if align < region.align {
+            bail!("alignment must be at least {}: {}", region.align, align);
+        }
+        let first = region.start.next_multiple_of(align);
+        let last = (region.start + region.size - region.len) & !(align - 1);
+        if first > last {
+            bail!("no {}-byte aligned request fits in the region", align);
         }
-        DmaPattern::FIX => addr,
-        DmaPattern::RANDOM => start + (rand::random::<u64>() % (size - len)) & !0xFFF,
+        Ok(Box::new(Random { first, last, align }))
+    }
+}
+
+impl AccessPattern for Random {
+    fn next_addr(&mut self) -> u64 {
+        let n = (self.last - self.first) / self.align + 1;
+        self.first + (rand::random::<u64>() % n) * self.align
+    }
+}
+
+/// Zipfian distribution over slots, where the first slots are the most popular
+///
+/// Based on "Quickly Generating Billion-Record Synthetic Databases" (Gray et al., SIGMOD '94).
+#[derive(Debug)]
+struct Zipf {
+    region: Region,
+    n: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipf {
+    fn build(region: Region, arg: Option<&str>) -> Result<Box<dyn AccessPattern>> {
+        let theta = parse_arg::<f64>("zipf", arg)?.unwrap_or(0.99);
+        if !(theta > 0.0 && theta < 1.0) {
+            bail!("theta must be in (0, 1): {}", theta);
+        }
+        let n = region.slots()?;
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zetan = zeta(n);
+        Ok(Box::new(Zipf {
+            region,
+            n,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta(2) / zetan),
+        }))
+    }
+}
+
+impl AccessPattern for Zipf {
+    fn next_addr(&mut self) -> u64 {
+        let u = rand::random::<f64>();
+        let uz = u * self.zetan;
+        let slot = if uz < 1.0 {
+            0
+        } else if uz < 1.0 + 0.5f64.powf(self.theta) {
+            1
+        } else {
+            (self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64
+        };
+        self.region.start + slot.min(self.n - 1) * self.region.len
+    }
+}
+
+/// Requests that cross each 4 KiB boundary in the region in turn
+#[derive(Debug)]
+struct PageCross {
+    region: Region,
+    next: u64,
+}
+
+impl PageCross {
+    const PAGE_SIZE: u64 = 0x1000;
+
+    fn build(region: Region, _: Option<&str>) -> Result<Box<dyn AccessPattern>> {
+        if region.len < 8 || region.len > Self::PAGE_SIZE {
+            bail!("cross requires dma_len between 8 and 4096");
+        }
+        let mut pattern = PageCross { region, next: 0 };
+        pattern.next = pattern.first();
+        if pattern.next + region.len > region.start + region.size {
+            bail!("cross requires a region that contains a 4 KiB boundary");
+        }
+        Ok(Box::new(pattern))
+    }
+
+    // Half of the request is on each side of the first boundary
+    fn first(&self) -> u64 {
+        let boundary = (self.region.start & !(Self::PAGE_SIZE - 1)) + Self::PAGE_SIZE;
+        boundary - ((self.region.len / 2) & !0x3)
+    }
+}
+
+impl AccessPattern for PageCross {
+    fn next_addr(&mut self) -> u64 {
+        let addr = self.next;
+        self.next += Self::PAGE_SIZE;
+        if self.next + self.region.len > self.region.start + self.region.size {
+            self.next = self.first();
+        }
+        addr
+    }
+}
+
+/// Addresses read from a file, one per line (decimal or 0x-prefixed hex).
+/// Empty lines and lines starting with '#' are ignored.
+/// The addresses are used as is regardless of the region, and every thread replays them.
+/// They must be DW-aligned for DMA write.
+#[derive(Debug)]
+struct Trace {
+    addrs: Vec<u64>,
+    next: usize,
+}
+
+impl Trace {
+    fn build(region: Region, arg: Option<&str>) -> Result<Box<dyn AccessPattern>> {
+        let path =
+            arg.ok_or_else(|| anyhow::anyhow!("trace requires a file, e.g., trace:addrs.txt"))?;
+        let content = std::fs::read_to_string(path)?;
+        let addrs = content
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| {
+                parse_int::parse::<u64>(l)
+                    .map_err(|_| anyhow::anyhow!("Invalid address in {}: {}", path, l))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if addrs.is_empty() {
+            bail!("no addresses in {}", path);
+        }
+        if let Some(addr) = addrs.iter().find(|a| !a.is_multiple_of(region.align)) {
+            bail!(
+                "{:#x} in {} is not {}-byte aligned",
+                addr,
+                path,
+                region.align
+            );
+        }
+        Ok(Box::new(Trace { addrs, next: 0 }))
+    }
+}
+
+impl AccessPattern for Trace {
+    fn next_addr(&mut self) -> u64 {
+        let addr = self.addrs[self.next];
+        self.next = (self.next + 1) % self.addrs.len();
+        addr
     }
 }
 
@@ -129,28 +734,377 @@ struct ThreadParam {
     region_size: usize,
     dma_len: usize,
     mrrs: usize,
+    batch: usize,
     count: u32,
     interval: u64,
+    /// Open-loop mode, and whether tokens are bits (bandwidth) or transactions (rate)
+    pacer: Option<(Pacer, bool)>,
     latency: bool,
     dir: DmaDirection,
-    pattern: DmaPattern,
-    ntrans: Arc<AtomicU64>,
-    nbytes: Arc<AtomicU64>,
+    pattern: Box<dyn AccessPattern>,
+    mode: BenchMode,
+    payload: Payload,
+    retries: u32,
+    keep_going: bool,
+    counters: Arc<Counters>,
+}
+
+/// Transactions, bytes, errors and latency of a thread
+///
+/// Timeouts, UR completions, malformed datagrams and stale completions
+/// are taken from the statistics of the thread's `NetTlp`.
+#[derive(Debug)]
+struct Counters {
+    nettlp: Arc<NetTlp>,
+    read_trans: AtomicU64,
+    read_bytes: AtomicU64,
+    write_trans: AtomicU64,
+    write_bytes: AtomicU64,
+    /// Requests that failed (after retries)
+    errors: AtomicU64,
+    retries: AtomicU64,
+    /// Latency (ns) since the last report
+    latency: Mutex<Histogram<u64>>,
+    /// Latency (ns) of the whole run, updated at each report
+    latency_total: Mutex<Histogram<u64>>,
+}
+
+impl Counters {
+    fn new(nettlp: Arc<NetTlp>) -> Self {
+        Counters {
+            nettlp,
+            read_trans: AtomicU64::new(0),
+            read_bytes: AtomicU64::new(0),
+            write_trans: AtomicU64::new(0),
+            write_bytes: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            latency: Mutex::new(new_histogram()),
+            latency_total: Mutex::new(new_histogram()),
+        }
+    }
+
+    fn load(&self) -> Sample {
+        let stats = self.nettlp.stats();
+        Sample([
+            self.read_bytes.load(Ordering::SeqCst),
+            self.read_trans.load(Ordering::SeqCst),
+            self.write_bytes.load(Ordering::SeqCst),
+            self.write_trans.load(Ordering::SeqCst),
+            self.errors.load(Ordering::SeqCst),
+            stats.timeouts,
+            stats.unsupported,
+            stats.malformed,
+            stats.stale,
+            self.retries.load(Ordering::SeqCst),
+        ])
+    }
+
+    // Move the latency since the last report to the total and return it
+    fn take_latency(&self) -> Histogram<u64> {
+        let mut latency = self.latency.lock().unwrap();
+        let interval = latency.clone();
+        latency.reset();
+        drop(latency);
+        self.latency_total.lock().unwrap().add(&interval).unwrap();
+        interval
+    }
+}
+
+/// Values of `Counters` at some point, or the difference of them
+#[derive(Copy, Clone, Debug, Default)]
+struct Sample([u64; 10]);
+
+impl Sample {
+    const FIELDS: [&'static str; 10] = [
+        "read_bytes",
+        "read_trans",
+        "write_bytes",
+        "write_trans",
+        "errors",
+        "timeouts",
+        "unsupported",
+        "malformed",
+        "stale",
+        "retries",
+    ];
+
+    fn sum(samples: &[Sample]) -> Sample {
+        Sample(std::array::from_fn(|i| {
+            samples.iter().map(|s| s.0[i]).sum()
+        }))
+    }
+
+    fn sub(&self, before: &Sample) -> Sample {
+        Sample(std::array::from_fn(|i| self.0[i] - before.0[i]))
+    }
+
+    fn to_json(self) -> serde_json::Map<String, serde_json::Value> {
+        Self::FIELDS
+            .iter()
+            .zip(self.0)
+            .map(|(k, v)| (k.to_string(), v.into()))
+            .collect()
+    }
+
+    fn to_text(self) -> String {
+        let [read_bytes, read_trans, write_bytes, write_trans, ..] = self.0;
+        format!(
+            "read {} bps, {} tps, write {} bps, {} tps{}",
+            read_bytes * 8,
+            read_trans,
+            write_bytes * 8,
+            write_trans,
+            self.errors_text()
+        )
+    }
+
+    // Errors if any
+    fn errors_text(self) -> String {
+        let [.., errors, timeouts, unsupported, malformed, stale, retries] = self.0;
+        if errors + timeouts + unsupported + malformed + stale + retries == 0 {
+            return String::new();
+        }
+        format!(
+            ", errors {} (timeouts {}, UR {}, malformed {}, stale {}), retries {}",
+            errors, timeouts, unsupported, malformed, stale, retries
+        )
+    }
 }
 
-// TODO: Support DMA write
-fn bench_thread(nettlp: NetTlp, param: ThreadParam) {
+/// Prints records in `Format`
+///
+/// Records are per interval (`time` is the number of seconds since the start)
+/// or for the whole run (the summary, with the elapsed time), for each thread and for all threads.
+#[derive(Debug)]
+struct Reporter {
+    format: Format,
+    latency: bool,
+}
+
+impl Reporter {
+    const LATENCY_FIELDS: [&'static str; 7] =
+        ["min", "mean", "p50", "p99", "p999", "max", "samples"];
+
+    fn header(&self) {
+        if self.format == Format::Csv {
+            let latency: Vec<_> = Self::LATENCY_FIELDS
+                .iter()
+                .map(|f| format!("latency_{}", f))
+                .collect();
+            println!(
+                "type,time,thread,{},{}",
+                Sample::FIELDS.join(","),
+                latency.join(",")
+            );
+        }
+    }
+
+    fn interval(&self, time: u64, samples: &[Sample], latency: &[Histogram<u64>]) {
+        if self.format == Format::Text {
+            println!("{}: {}", time, Sample::sum(samples).to_text());
+            if let Some(h) = merge(latency) {
+                println!("{}: latency {}", time, latency_summary(&h));
+            }
+        } else {
+            self.records("interval", time.into(), samples, latency);
+        }
+    }
+
+    fn summary(
+        &self,
+        config: &serde_json::Value,
+        elapsed: f64,
+        samples: &[Sample],
+        latency: &[Histogram<u64>],
+    ) {
+        match self.format {
+            Format::Text => {
+                let total = Sample::sum(samples);
+                let [read_bytes, read_trans, write_bytes, write_trans, ..] = total.0;
+                println!(
+                    "total: read {} bytes, {} trans, write {} bytes, {} trans in {:.1} sec{}",
+                    read_bytes,
+                    read_trans,
+                    write_bytes,
+                    write_trans,
+                    elapsed,
+                    total.errors_text()
+                );
+                for (n, h) in latency.iter().enumerate() {
+                    println!("thread {}: latency {}", n, latency_summary(h));
+                }
+                if let Some(h) = merge(latency) {
+                    println!("total: latency {}", latency_summary(&h));
+                }
+            }
+            Format::Json => {
+                let threads: Vec<_> = samples
+                    .iter()
+                    .enumerate()
+                    .map(|(n, s)| self.json(Some(n), s, latency.get(n)))
+                    .collect();
+                let total = self.json(None, &Sample::sum(samples), merge(latency).as_ref());
+                let record = serde_json::json!({
+                    "type": "summary",
+                    "config": config,
+                    "elapsed": elapsed,
+                    "threads": threads,
+                    "total": total,
+                });
+                println!("{}", record);
+            }
+            Format::Csv => self.records("summary", elapsed.into(), samples, latency),
+        }
+    }
+
+    // Records of each thread and all threads in JSON or CSV
+    fn records(
+        &self,
+        ty: &str,
+        time: serde_json::Value,
+        samples: &[Sample],
+        latency: &[Histogram<u64>],
+    ) {
+        let total = Sample::sum(samples);
+        let total_latency = merge(latency);
+        let threads = samples
+            .iter()
+            .enumerate()
+            .map(|(n, s)| (Some(n), s, latency.get(n)));
+        let all = std::iter::once((None, &total, total_latency.as_ref()));
+        for (thread, sample, latency) in threads.chain(all) {
+            if self.format == Format::Csv {
+                self.csv(ty, &time, thread, sample, latency);
+            } else {
+                let mut record = serde_json::Map::new();
+                record.insert("type".into(), ty.into());
+                record.insert("time".into(), time.clone());
+                record.extend(self.json(thread, sample, latency));
+                println!("{}", serde_json::Value::Object(record));
+            }
+        }
+    }
+
+    fn json(
+        &self,
+        thread: Option<usize>,
+        sample: &Sample,
+        latency: Option<&Histogram<u64>>,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        let mut record = serde_json::Map::new();
+        record.insert("thread".into(), thread.into());
+        record.extend(sample.to_json());
+        if let Some(h) = latency.filter(|_| self.latency) {
+            let latency: serde_json::Map<_, _> = Self::LATENCY_FIELDS
+                .iter()
+                .zip(latency_values(h))
+                .map(|(k, v)| (k.to_string(), v.into()))
+                .collect();
+            record.insert("latency".into(), latency.into());
+        }
+        record
+    }
+
+    fn csv(
+        &self,
+        ty: &str,
+        time: &serde_json::Value,
+        thread: Option<usize>,
+        sample: &Sample,
+        latency: Option<&Histogram<u64>>,
+    ) {
+        let mut row = vec![
+            ty.to_string(),
+            time.to_string(),
+            thread.map_or(String::new(), |n| n.to_string()),
+        ];
+        row.extend(sample.0.iter().map(|v| v.to_string()));
+        match latency.filter(|_| self.latency) {
+            Some(h) => row.extend(latency_values(h).iter().map(|v| v.to_string())),
+            None => row.extend(Self::LATENCY_FIELDS.iter().map(|_| String::new())),
+        }
+        println!("{}", row.join(","));
+    }
+}
+
+// Merge histograms of threads
+fn merge(latency: &[Histogram<u64>]) -> Option<Histogram<u64>> {
+    let mut iter = latency.iter();
+    let mut total = iter.next()?.clone();
+    for h in iter {
+        total.add(h).unwrap();
+    }
+    Some(total)
+}
+
+// Latency from 1 ns to 60 s with 3 significant digits
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, 60_000_000_000, 3).unwrap()
+}
+
+// min, mean, p50, p99, p99.9, max and the number of samples
+fn latency_values(h: &Histogram<u64>) -> [u64; 7] {
+    [
+        h.min(),
+        h.mean().round() as u64,
+        h.value_at_quantile(0.5),
+        h.value_at_quantile(0.99),
+        h.value_at_quantile(0.999),
+        h.max(),
+        h.len(),
+    ]
+}
+
+fn latency_summary(h: &Histogram<u64>) -> String {
+    let [min, mean, p50, p99, p999, max, samples] = latency_values(h);
+    format!(
+        "min {} mean {} p50 {} p99 {} p99.9 {} max {} nsec ({} samples)",
+        min, mean, p50, p99, p999, max, samples
+    )
+}
+
+// Write the latency distribution for plotting
+fn write_latency(path: &std::path::Path, h: &Histogram<u64>) -> Result<()> {
+    use std::io::Write;
+
+    let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let rows = h.iter_quantiles(1).map(|v| {
+        (
+            v.quantile_iterated_to(),
+            v.value_iterated_to(),
+            v.count_since_last_iteration(),
+        )
+    });
+    if path.extension().is_some_and(|e| e == "json") {
+        let rows: Vec<_> = rows
+            .map(|(q, v, n)| serde_json::json!({"percentile": q * 100.0, "latency_ns": v, "count": n}))
+            .collect();
+        serde_json::to_writer_pretty(&mut f, &rows)?;
+    } else {
+        writeln!(f, "percentile,latency_ns,count")?;
+        for (q, v, n) in rows {
+            writeln!(f, "{},{},{}", q * 100.0, v, n)?;
+        }
+    }
+    f.flush()?;
+    Ok(())
+}
+
+fn bench_thread(nettlp: Arc<NetTlp>, mut param: ThreadParam) -> Result<()> {
     let cores = [param.cpu as usize];
-    affinity::set_thread_affinity(&cores).unwrap();
+    affinity::set_thread_affinity(cores).unwrap();
 
     let mut count = 0;
     let len = param.dma_len;
     let mut buf = bytes::BytesMut::with_capacity(len);
-    let mut addr = param.region_addr;
+    let mut batch_bufs = vec![vec![0u8; len]; param.batch];
+    let mut write_buf = vec![0u8; len];
+    param.payload.fill(&mut write_buf);
 
-    println!(
-        "start on cpu {}, address {:#x}, size {}, dma_len {}, mrrs {}",
-        param.cpu, param.region_addr, param.region_size, len, param.mrrs
+    eprintln!(
+        "start on cpu {}, address {:#x}, size {}, dma_len {}, mrrs {}, mode {:?}",
+        param.cpu, param.region_addr, param.region_size, len, param.mrrs, param.mode
     );
 
     loop {
@@ -158,31 +1112,78 @@ fn bench_thread(nettlp: NetTlp, param: ThreadParam) {
             break;
         }
 
-        buf.clear();
+        let is_read = param.mode.next_is_read();
+        let addrs: Vec<_> = (0..param.batch.max(1))
+            .map(|_| param.pattern.next_addr())
+            .collect();
 
-        if param.latency {
-            let now = std::time::SystemTime::now();
-            nettlp.dma_read(addr, &mut buf, len).unwrap();
-            println!(
-                "latency: cpu on {}, {} nsec",
-                param.cpu,
-                now.elapsed().unwrap().as_nanos()
-            );
+        let now = match &mut param.pacer {
+            Some((pacer, true)) => pacer.acquire((len * addrs.len() * 8) as f64),
+            Some((pacer, false)) => pacer.acquire(addrs.len() as f64),
+            None => Instant::now(),
+        };
+        let mut attempt = || -> Result<(), Error> {
+            if !is_read {
+                // Writes are posted, so `batch` writes are just issued back to back
+                for &addr in addrs.iter() {
+                    // `dma_write()` panics on a non DW-aligned address
+                    if addr & 0x3 != 0 {
+                        return Err(Error::InvalidAddress(addr));
+                    }
+                    nettlp.dma_write(addr, &write_buf)?;
+                }
+            } else if param.batch > 1 {
+                let mut reqs: Vec<_> = addrs
+                    .iter()
+                    .zip(batch_bufs.iter_mut())
+                    .map(|(&addr, buf)| DmaReadRequest { addr, buf })
+                    .collect();
+                nettlp.dma_read_batch(&mut reqs)?;
+            } else {
+                buf.clear();
+                nettlp.dma_read(addrs[0], &mut buf, len)?;
+            }
+            Ok(())
+        };
+        let mut result = attempt();
+        let mut retries = 0;
+        while result.is_err() {
+            // Completions of the failed request may arrive later
+            nettlp.transport().discard_pending();
+            if retries >= param.retries {
+                break;
+            }
+            retries += 1;
+            param.counters.retries.fetch_add(1, Ordering::SeqCst);
+            result = attempt();
+        }
+        if let Err(e) = result {
+            param.counters.errors.fetch_add(1, Ordering::SeqCst);
+            if !param.keep_going {
+                RUNNING.store(false, Ordering::SeqCst);
+                return Err(anyhow::Error::new(e).context(format!(
+                    "{} at {:#x} failed on cpu {}",
+                    if is_read { "DMA read" } else { "DMA write" },
+                    addrs[0],
+                    param.cpu
+                )));
+            }
         } else {
-            nettlp.dma_read(addr, &mut buf, len).unwrap();
+            if param.latency {
+                let ns = now.elapsed().as_nanos() as u64;
+                param.counters.latency.lock().unwrap().saturating_record(ns);
+            }
+            let ntrans = addrs.len();
+            let (trans, bytes) = if is_read {
+                (&param.counters.read_trans, &param.counters.read_bytes)
+            } else {
+                (&param.counters.write_trans, &param.counters.write_bytes)
+            };
+            trans.fetch_add(ntrans as u64, Ordering::SeqCst);
+            bytes.fetch_add((len * ntrans) as u64, Ordering::SeqCst);
         }
-        param.ntrans.fetch_add(1, Ordering::SeqCst);
-        param.nbytes.fetch_add(len as u64, Ordering::SeqCst);
         count += 1;
 
-        addr = next_addr(
-            param.region_addr,
-            param.region_size as u64,
-            addr,
-            len as u64,
-            param.pattern,
-        );
-
         if param.count > 0 && count >= param.count {
             RUNNING.store(false, Ordering::SeqCst);
             break;
@@ -192,14 +1193,15 @@ fn bench_thread(nettlp: NetTlp, param: ThreadParam) {
             std::thread::sleep(std::time::Duration::from_millis(param.interval));
         }
     }
+    Ok(())
 }
 
-fn count_thread(ntrans: Vec<Arc<AtomicU64>>, nbytes: Vec<Arc<AtomicU64>>, duration: u32) {
+fn count_thread(counters: Vec<Arc<Counters>>, duration: u32, reporter: &Reporter) {
     // run this thread on the last cpu
     let cores = [affinity::get_core_num() - 1];
-    affinity::set_thread_affinity(&cores).unwrap();
+    affinity::set_thread_affinity(cores).unwrap();
 
-    println!("start count thread on {}", cores[0]);
+    eprintln!("start count thread on {}", cores[0]);
 
     let mut count = 0;
 
@@ -208,29 +1210,24 @@ fn count_thread(ntrans: Vec<Arc<AtomicU64>>, nbytes: Vec<Arc<AtomicU64>>, durati
             break;
         }
 
-        fn collect(v: &Vec<Arc<AtomicU64>>) -> Vec<u64> {
-            v.iter().map(|x| x.load(Ordering::SeqCst)).collect()
-        }
-
-        let before_ntrans: Vec<u64> = collect(&ntrans);
-        let before_nbytes: Vec<u64> = collect(&nbytes);
+        let before: Vec<_> = counters.iter().map(|c| c.load()).collect();
 
         std::thread::sleep(std::time::Duration::from_secs(1));
         if !RUNNING.load(Ordering::SeqCst) {
             break;
         }
 
-        let after_ntrans: Vec<u64> = collect(&ntrans);
-        let after_nbytes: Vec<u64> = collect(&nbytes);
-
-        fn diff_sum(before: &[u64], after: &[u64]) -> u64 {
-            before.iter().zip(after.iter()).map(|(x, y)| y - x).sum()
-        }
-
-        let ntrans: u64 = diff_sum(&before_ntrans, &after_ntrans);
-        let nbytes: u64 = diff_sum(&before_nbytes, &after_nbytes);
-
-        println!("{}: {} bps, {} tps", count, nbytes * 8, ntrans);
+        let samples: Vec<_> = counters
+            .iter()
+            .zip(&before)
+            .map(|(c, before)| c.load().sub(before))
+            .collect();
+        let latency: Vec<_> = if reporter.latency {
+            counters.iter().map(|c| c.take_latency()).collect()
+        } else {
+            vec![]
+        };
+        reporter.interval(count as u64, &samples, &latency);
 
         count += 1;
         if duration > 0 && count >= duration {
@@ -241,64 +1238,128 @@ fn count_thread(ntrans: Vec<Arc<AtomicU64>>, nbytes: Vec<Arc<AtomicU64>>, durati
 }
 
 fn benchmark(args: &Args) -> Result<()> {
+    if args.mode.has_write() && (!args.dma_len.is_multiple_of(4) || args.region_addr & 0x3 != 0) {
+        bail!("--dma-len and --region-addr must be DW-aligned for DMA write");
+    }
+
     let mut threads = vec![];
-    let mut ntrans_ = vec![];
-    let mut nbytes_ = vec![];
+    let mut counters_ = vec![];
     let duration = args.duration;
+    let config = args.config();
+    let reporter = Arc::new(Reporter {
+        format: args.format,
+        latency: args.latency,
+    });
+    if let Some(path) = &args.config_output {
+        std::fs::write(path, format!("{:#}\n", config))?;
+    }
+    reporter.header();
+    let start = Instant::now();
+
+    // Each thread accesses its own 4 KiB-aligned part of the region
+    let region_size = (args.region_size / (args.nthreads as usize)) & !0xFFF;
+    if region_size == 0 {
+        bail!(
+            "--region-size must be at least 4 KiB per thread ({} threads)",
+            args.nthreads
+        );
+    }
+
+    // Each thread uses its own tags, one for each read request in flight
+    let ntags = args.batch.max(1);
+    let last_tag = args.tag as usize + ntags * args.nthreads as usize;
+    // The end of the tag range is exclusive, so tag 255 is not used
+    if last_tag > u8::MAX as usize {
+        bail!(
+            "{} threads with {} tags each from tag {} exceed tag 254",
+            args.nthreads,
+            ntags,
+            args.tag
+        );
+    }
 
     for n in 0..args.nthreads {
         let cpu = n;
-        let tag = n;
-        let region_size = args.region_size / (args.nthreads as usize);
+        let first_tag = (args.tag as usize + ntags * n as usize) as u8;
+        let tags = first_tag..(first_tag as usize + ntags) as u8;
         let region_addr = args.region_addr + (region_size * n as usize) as u64;
         let dir = DmaDirection::DmaIssuedByLibTLP;
-        let ntrans = Arc::new(AtomicU64::new(0));
-        let ntrans_clone = Arc::clone(&ntrans);
-        ntrans_.push(ntrans);
-        let nbytes = Arc::new(AtomicU64::new(0));
-        let nbytes_clone = Arc::clone(&nbytes);
-        nbytes_.push(nbytes);
-        let nettlp = NetTlp::new(
+        let nettlp = Arc::new(NetTlp::with_tags(
             args.bdf,
             args.local_addr,
             args.remote_addr,
-            tag,
+            tags,
             args.mrrs,
-            DmaDirection::DmaIssuedByLibTLP,
-        )?;
+            dir,
+        )?);
+        let counters = Arc::new(Counters::new(Arc::clone(&nettlp)));
+        counters_.push(Arc::clone(&counters));
         let param = ThreadParam {
             cpu,
             region_addr,
             region_size,
             dma_len: args.dma_len,
             mrrs: args.mrrs,
+            batch: args.batch,
             count: args.count,
             latency: args.latency,
             interval: args.interval,
+            pacer: args.pacer(),
             dir,
-            pattern: args.pattern,
-            ntrans: ntrans_clone,
-            nbytes: nbytes_clone,
+            pattern: args.pattern.build(Region {
+                start: region_addr,
+                size: region_size as u64,
+                len: args.dma_len as u64,
+                align: if args.mode.has_write() { 4 } else { 1 },
+            })?,
+            mode: args.mode,
+            payload: args.payload,
+            retries: args.retries,
+            keep_going: args.keep_going,
+            counters,
         };
         threads.push(thread::spawn(move || bench_thread(nettlp, param)));
     }
 
-    threads.push(thread::spawn(move || {
-        count_thread(ntrans_, nbytes_, duration)
-    }));
+    let counters = counters_.clone();
+    let reporter_ = Arc::clone(&reporter);
+    let counter = thread::spawn(move || count_thread(counters_, duration, &reporter_));
 
-    for th in threads {
-        th.join().unwrap();
+    let results: Vec<_> = threads
+        .into_iter()
+        .map(|th| {
+            th.join()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("benchmark thread panicked")))
+        })
+        .collect();
+    counter.join().unwrap();
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let samples: Vec<_> = counters.iter().map(|c| c.load()).collect();
+    let latency: Vec<_> = if args.latency {
+        counters
+            .iter()
+            .map(|c| {
+                c.take_latency();
+                c.latency_total.lock().unwrap().clone()
+            })
+            .collect()
+    } else {
+        vec![]
+    };
+    reporter.summary(&config, elapsed, &samples, &latency);
+    if let (Some(path), Some(total)) = (&args.latency_output, merge(&latency)) {
+        write_latency(path, &total)?;
     }
 
-    Ok(())
+    results.into_iter().collect()
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
     ctrlc::set_handler(|| {
-        println!("Received Ctrl-C, quitting...");
+        eprintln!("Received Ctrl-C, quitting...");
         RUNNING.store(false, Ordering::SeqCst);
     })?;
 
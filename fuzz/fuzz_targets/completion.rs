@@ -0,0 +1,81 @@
+//! Feed arbitrary datagrams as completions of DMA, I/O and AtomicOp reads.
+//! Malformed completions must be reported as errors, never as panics.
+#![no_main]
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use libtlp::pci::Bdf;
+use libtlp::{DmaReadRequest, Error, NetTlp, Transport};
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Pretend to be the adapter by replaying datagrams, and time out immediately when they run out
+#[derive(Debug)]
+struct Replay(Mutex<VecDeque<Vec<u8>>>);
+
+impl Transport for Replay {
+    fn send(&self, _packet: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize, Error> {
+        let packet = self.0.lock().unwrap().pop_front().ok_or(Error::Timeout)?;
+        let n = std::cmp::min(packet.len(), buf.len());
+        buf[..n].copy_from_slice(&packet[..n]);
+        Ok(n)
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    DmaRead { addr: u64, len: u16 },
+    DmaReadBatch { reqs: Vec<(u64, u16)> },
+    IoRead { addr: u32, size: u8 },
+    FetchAdd { addr: u64 },
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    tag: u8,
+    /// 1 + ntags % 8 tags from `tag` (< 248)
+    ntags: u8,
+    /// 128 << (mrrs % 6)
+    mrrs: u8,
+    op: Op,
+    datagrams: Vec<Vec<u8>>,
+}
+
+// Requests are kept within 48-bit addresses
+fn addr(addr: u64) -> u64 {
+    addr & ((1 << 48) - 1)
+}
+
+fuzz_target!(|input: Input| {
+    let transport = Replay(Mutex::new(input.datagrams.into()));
+    let mrrs = 128 << (input.mrrs % 6);
+    let tag = input.tag % 248;
+    let tags = tag..tag + 1 + input.ntags % 8;
+    let nettlp = NetTlp::with_transport_tags(Bdf::new(1, 0, 0), tags, mrrs, transport);
+
+    let _ = match input.op {
+        Op::DmaRead { addr: a, len: l } => nettlp.dma_read(addr(a), &mut vec![], l as usize),
+        Op::DmaReadBatch { reqs } => {
+            let reqs: Vec<_> = reqs.into_iter().take(16).collect();
+            let mut bufs: Vec<_> = reqs.iter().map(|&(_, l)| vec![0u8; l as usize]).collect();
+            let mut reqs: Vec<_> = reqs
+                .iter()
+                .zip(bufs.iter_mut())
+                .map(|(&(a, _), buf)| DmaReadRequest { addr: addr(a), buf })
+                .collect();
+            nettlp.dma_read_batch(&mut reqs)
+        }
+        Op::IoRead { addr: a, size } => match size % 3 {
+            0 => nettlp.io_read_u8(a).map(drop),
+            1 => nettlp.io_read_u16(a & !0x1).map(drop),
+            _ => nettlp.io_read_u32(a & !0x3).map(drop),
+        },
+        Op::FetchAdd { addr: a } => nettlp.atomic_fetch_add(addr(a), 1u64).map(drop),
+    };
+});
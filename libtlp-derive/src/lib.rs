@@ -0,0 +1,226 @@
+//! Derive macros for libtlp
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitInt};
+
+/// Derive `libtlp::phys::PhysStruct`
+///
+/// This generates `<Name>Fields`, a struct of `PhysPtr`s to each field,
+/// so that a field can be read or written without accessing the whole struct:
+///
+/// ```
+/// use libtlp::phys::{Be32, PhysStruct};
+/// use libtlp::{ChannelTransport, NetTlp, PhysPtr};
+///
+/// #[repr(C)]
+/// #[derive(PhysStruct)]
+/// #[phys(size = 16)]
+/// struct Desc {
+///     addr: u64,
+///     #[phys(offset = 8)]
+///     len: Be32,
+///     flags: u32,
+/// }
+///
+/// let (transport, _adapter) = ChannelTransport::pair();
+/// let nettlp = NetTlp::with_transport(libtlp::pci::Bdf::new(1, 0, 0), 0, 512, transport);
+/// let len: PhysPtr<'_, Be32> = PhysPtr::<Desc>::new(&nettlp, 0x1000).fields().len;
+/// assert_eq!(len.addr(), 0x1008);
+/// ```
+///
+/// The struct must be `#[repr(C)]` (optionally packed).
+/// Fields in a specific byte order are declared with `libtlp::phys::Be32`, `Le32` etc.,
+/// so that reading the whole struct and reading the field agree.
+///
+/// Attributes:
+/// - `#[phys(size = N)]` on the struct asserts the size of the struct at compile time
+/// - `#[phys(offset = N)]` on a field asserts the offset of the field at compile time
+///
+/// A wrong size or offset is a compile error:
+///
+/// ```compile_fail
+/// # use libtlp::phys::PhysStruct;
+/// #[repr(C)]
+/// #[derive(PhysStruct)]
+/// #[phys(size = 12)]
+/// struct Desc {
+///     addr: u64,
+///     len: u32,
+///     flags: u32,
+/// }
+/// ```
+///
+/// ```compile_fail
+/// # use libtlp::phys::PhysStruct;
+/// #[repr(C)]
+/// #[derive(PhysStruct)]
+/// struct Desc {
+///     addr: u64,
+///     #[phys(offset = 4)]
+///     len: u32,
+///     flags: u32,
+/// }
+/// ```
+///
+/// So is a struct without `#[repr(C)]`:
+///
+/// ```compile_fail
+/// # use libtlp::phys::PhysStruct;
+/// #[derive(PhysStruct)]
+/// struct Desc {
+///     addr: u64,
+///     len: u32,
+///     flags: u32,
+/// }
+/// ```
+///
+/// `be` and `le` attributes are rejected; use `Be32`, `Le32` etc. instead:
+///
+/// ```compile_fail
+/// # use libtlp::phys::PhysStruct;
+/// #[repr(C)]
+/// #[derive(PhysStruct)]
+/// struct Desc {
+///     addr: u64,
+///     #[phys(be)]
+///     len: u32,
+///     flags: u32,
+/// }
+/// ```
+#[proc_macro_derive(PhysStruct, attributes(phys))]
+pub fn derive_phys_struct(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    let vis = &input.vis;
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "PhysStruct does not support generics",
+        ));
+    }
+    let fields = match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(f) => &f.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    "PhysStruct requires named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "PhysStruct can be derived only for structs",
+            ))
+        }
+    };
+
+    let mut repr_c = false;
+    let mut size = None;
+    for attr in &input.attrs {
+        if attr.path().is_ident("repr") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("C") {
+                    repr_c = true;
+                }
+                // Skip arguments such as packed(2)
+                if meta.input.peek(syn::token::Paren) {
+                    let _content;
+                    syn::parenthesized!(_content in meta.input);
+                }
+                Ok(())
+            })?;
+        } else if attr.path().is_ident("phys") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("size") {
+                    size = Some(meta.value()?.parse::<LitInt>()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("unknown attribute (expected `size = N`)"))
+                }
+            })?;
+        }
+    }
+    if !repr_c {
+        return Err(syn::Error::new_spanned(
+            ident,
+            "PhysStruct requires #[repr(C)] for a stable layout",
+        ));
+    }
+
+    let fields_ident = format_ident!("{}Fields", ident);
+    let mut decls = vec![];
+    let mut inits = vec![];
+    let mut asserts = vec![];
+    if let Some(size) = size {
+        let msg = format!("size of {} is not {}", ident, size);
+        asserts.push(quote! {
+            assert!(::core::mem::size_of::<#ident>() == #size, #msg);
+        });
+    }
+    for field in fields {
+        let name = field.ident.as_ref().unwrap();
+        let fvis = &field.vis;
+        let ty = &field.ty;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("phys")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("offset") {
+                    let offset = meta.value()?.parse::<LitInt>()?;
+                    let msg = format!("offset of {}::{} is not {}", ident, name, offset);
+                    asserts.push(quote! {
+                        assert!(::core::mem::offset_of!(#ident, #name) == #offset, #msg);
+                    });
+                    Ok(())
+                } else if meta.path.is_ident("be") || meta.path.is_ident("le") {
+                    // Only the field pointer would be converted, not the struct
+                    Err(meta.error(
+                        "`be` and `le` are not supported; \
+                         declare the field as `libtlp::phys::Be32`, `Le32` etc.",
+                    ))
+                } else {
+                    Err(meta.error("unknown attribute (expected `offset = N`)"))
+                }
+            })?;
+        }
+
+        decls.push(quote! { #fvis #name: ::libtlp::PhysPtr<'a, #ty> });
+        inits.push(quote! {
+            #name: ptr.byte_add(::core::mem::offset_of!(#ident, #name))
+        });
+    }
+
+    let doc = format!(
+        "Pointers to the fields of [`{}`] in host physical memory",
+        ident
+    );
+    Ok(quote! {
+        #[doc = #doc]
+        #[derive(Clone, Copy, Debug)]
+        #vis struct #fields_ident<'a> {
+            #(#decls,)*
+        }
+
+        impl ::libtlp::phys::PhysStruct for #ident {
+            type Fields<'a> = #fields_ident<'a>;
+
+            fn fields(ptr: ::libtlp::PhysPtr<'_, Self>) -> #fields_ident<'_> {
+                #fields_ident {
+                    #(#inits,)*
+                }
+            }
+        }
+
+        const _: () = {
+            #(#asserts)*
+        };
+    })
+}
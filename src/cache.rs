@@ -0,0 +1,334 @@
+use crate::error::Error;
+use crate::nettlp::NetTlp;
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::Mutex;
+
+use bytes::BufMut;
+use zerocopy::{AsBytes, FromBytes};
+
+const PAGE_SIZE: usize = 0x1000;
+const PAGE_MASK: u64 = !(PAGE_SIZE as u64 - 1);
+
+/// Hit/miss statistics of `CachedNetTlp`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of page accesses served from the cache
+    pub hits: u64,
+    /// Number of pages read from the host because they were not in the cache
+    pub misses: u64,
+    /// Number of pages evicted to make room for other pages
+    pub evictions: u64,
+    /// Number of page accesses that bypassed the cache because of a no-cache range
+    pub uncached: u64,
+}
+
+/// A page-granular LRU read cache over `NetTlp`
+///
+/// Page-table walks and traversal of kernel structures read the same pages many times.
+/// `CachedNetTlp` keeps up to `capacity` 4 KiB pages read from the host
+/// so that subsequent reads of those pages do not go over the network.
+///
+/// Writes are written through to the host and update cached pages.
+/// The cache cannot see writes made by the host itself (or by other handles),
+/// so call `invalidate()` when the memory may have changed.
+/// Ranges registered with `add_no_cache()` (e.g., MMIO registers) are always read from the host.
+///
+/// ```no_run
+/// use libtlp::CachedNetTlp;
+/// # use libtlp::{DmaDirection, NetTlp};
+/// # use libtlp::pci::Bdf;
+/// # use std::net::Ipv4Addr;
+///
+/// # let nettlp = NetTlp::new(Bdf::new(1, 0, 0), Ipv4Addr::new(192, 168, 10, 3),
+/// #     Ipv4Addr::new(192, 168, 10, 1), 0, 512, DmaDirection::DmaIssuedByLibTLP).unwrap();
+/// let cache = CachedNetTlp::new(&nettlp, 256);
+/// cache.add_no_cache(0xfe00_0000..0xfe10_0000);
+/// let mut pte = 0u64;
+/// cache.dma_read_t(0x1000, &mut pte).unwrap();
+/// cache.dma_read_t(0x1008, &mut pte).unwrap(); // served from the cache
+/// println!("{:?}", cache.stats());
+/// ```
+#[derive(Debug)]
+pub struct CachedNetTlp<'a> {
+    nettlp: &'a NetTlp,
+    // DMA is issued with the lock held, as requests of a handle cannot be in flight concurrently
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    capacity: usize,
+    // page address -> page
+    pages: HashMap<u64, CachedPage>,
+    // last use -> page address, the first entry is the least recently used page
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+    no_cache: Vec<Range<u64>>,
+    stats: CacheStats,
+}
+
+#[derive(Debug)]
+struct CachedPage {
+    data: Box<[u8; PAGE_SIZE]>,
+    last_use: u64,
+}
+
+impl<'a> CachedNetTlp<'a> {
+    /// Create a cache that holds up to `capacity` pages read through `nettlp`
+    pub fn new(nettlp: &'a NetTlp, capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must be at least one page");
+        CachedNetTlp {
+            nettlp,
+            state: Mutex::new(CacheState {
+                capacity,
+                pages: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                no_cache: vec![],
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    /// The underlying handle
+    pub fn nettlp(&self) -> &'a NetTlp {
+        self.nettlp
+    }
+
+    /// Read `sizeof(T)` bytes into `t` from a physical addr
+    pub fn dma_read_t<T: Sized + FromBytes + AsBytes>(
+        &self,
+        addr: u64,
+        t: &mut T,
+    ) -> Result<(), Error> {
+        let len = std::mem::size_of::<T>();
+        self.dma_read(addr, &mut t.as_bytes_mut(), len)
+    }
+
+    /// Read `len` bytes from a physical address `addr` into `buf`
+    ///
+    /// Pages not in the cache are read as a whole.
+    /// Pages in no-cache ranges are read in the same way as `NetTlp::dma_read()`.
+    pub fn dma_read<T: BufMut>(&self, addr: u64, buf: &mut T, len: usize) -> Result<(), Error> {
+        assert!(len <= buf.remaining_mut());
+        let mut state = self.state.lock().unwrap();
+        let mut p = addr;
+        let mut received = 0;
+        while received < len {
+            let page = p & PAGE_MASK;
+            let offset = (p - page) as usize;
+            let n = std::cmp::min(len - received, PAGE_SIZE - offset);
+            if state.is_no_cache(page) {
+                state.stats.uncached += 1;
+                self.nettlp.dma_read(p, buf, n)?;
+            } else {
+                let data = state.lookup(self.nettlp, page)?;
+                buf.put_slice(&data[offset..offset + n]);
+            }
+            received += n;
+            p += n as u64;
+        }
+        Ok(())
+    }
+
+    /// DMA write
+    ///
+    /// The data is written to the host, then cached pages are updated.
+    pub fn dma_write(&self, addr: u64, buf: &[u8]) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = self.nettlp.dma_write(addr, buf) {
+            // Part of the data may have been written
+            state.invalidate(addr..addr + buf.len() as u64);
+            return Err(e);
+        }
+        let end = addr + buf.len() as u64;
+        let mut p = addr;
+        while p < end {
+            let page = p & PAGE_MASK;
+            let offset = (p - page) as usize;
+            let n = std::cmp::min((end - p) as usize, PAGE_SIZE - offset);
+            if let Some(cached) = state.pages.get_mut(&page) {
+                let sent = (p - addr) as usize;
+                cached.data[offset..offset + n].copy_from_slice(&buf[sent..sent + n]);
+            }
+            p += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Write `T` in a memory `addr`
+    pub fn dma_write_t<T: Sized + AsBytes>(&self, addr: u64, t: T) -> Result<(), Error> {
+        self.dma_write(addr, t.as_bytes())
+    }
+
+    /// Never cache pages that overlap `range`
+    ///
+    /// Cached pages in the range are dropped.
+    pub fn add_no_cache(&self, range: Range<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.invalidate(range.clone());
+        state.no_cache.push(range);
+    }
+
+    /// Drop cached pages that overlap `range`
+    pub fn invalidate(&self, range: Range<u64>) {
+        self.state.lock().unwrap().invalidate(range);
+    }
+
+    /// Drop all cached pages
+    pub fn invalidate_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pages.clear();
+        state.lru.clear();
+    }
+
+    /// Number of cached pages
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().pages.len()
+    }
+
+    /// Returns true if no pages are cached
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Statistics since the creation or the last `reset_stats()`
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Reset the statistics
+    pub fn reset_stats(&self) {
+        self.state.lock().unwrap().stats = CacheStats::default();
+    }
+}
+
+impl CacheState {
+    fn is_no_cache(&self, page: u64) -> bool {
+        let end = page + PAGE_SIZE as u64;
+        self.no_cache.iter().any(|r| r.start < end && page < r.end)
+    }
+
+    // Return the page at `page`, reading it from the host on a miss
+    fn lookup(&mut self, nettlp: &NetTlp, page: u64) -> Result<&[u8; PAGE_SIZE], Error> {
+        self.clock += 1;
+        let now = self.clock;
+        if let Some(cached) = self.pages.get_mut(&page) {
+            self.stats.hits += 1;
+            self.lru.remove(&cached.last_use);
+            self.lru.insert(now, page);
+            cached.last_use = now;
+            return Ok(&self.pages[&page].data);
+        }
+
+        self.stats.misses += 1;
+        let mut data = Box::new([0u8; PAGE_SIZE]);
+        nettlp.dma_read(page, &mut &mut data[..], PAGE_SIZE)?;
+        if self.pages.len() >= self.capacity {
+            if let Some((_, victim)) = self.lru.pop_first() {
+                self.pages.remove(&victim);
+                self.stats.evictions += 1;
+            }
+        }
+        self.lru.insert(now, page);
+        let cached = self.pages.entry(page).or_insert(CachedPage {
+            data,
+            last_use: now,
+        });
+        Ok(&cached.data)
+    }
+
+    fn invalidate(&mut self, range: Range<u64>) {
+        let lru = &mut self.lru;
+        self.pages.retain(|&page, cached| {
+            let overlaps = range.start < page + PAGE_SIZE as u64 && page < range.end;
+            if overlaps {
+                lru.remove(&cached.last_use);
+            }
+            !overlaps
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci;
+    use crate::testutil::{respond, Mrd};
+    use crate::transport::ChannelTransport;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn lru_and_write_through() {
+        let (transport, adapter) = ChannelTransport::pair();
+        let nettlp = NetTlp::with_transport(pci::Bdf::new(1, 0, 0), 0, 128, transport);
+
+        // Pretend to be the adapter: reply bits 8-15 of the address plus bits 0-7
+        // as data, and count read requests
+        let reads = Arc::new(AtomicUsize::new(0));
+        let counter = reads.clone();
+        let th = respond(adapter, move |tlp| {
+            let Some(mrd) = Mrd::parse(tlp) else {
+                return vec![];
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            let v = ((mrd.addr >> 8) as u8).wrapping_add(mrd.addr as u8);
+            vec![mrd.reply(|_| v)]
+        });
+
+        let cache = CachedNetTlp::new(&nettlp, 2);
+        let mut v = 0u64;
+        cache.dma_read_t(0x1010, &mut v).unwrap();
+        assert_eq!(v, 0x1010101010101010);
+        cache.dma_read_t(0x1080, &mut v).unwrap();
+        assert_eq!(v, 0x9090909090909090);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                ..Default::default()
+            }
+        );
+
+        // Write-through
+        cache.dma_write_t(0x1020, 0x04030201u32).unwrap();
+        let mut w = 0u32;
+        cache.dma_read_t(0x1020, &mut w).unwrap();
+        assert_eq!(w, 0x04030201);
+
+        // No-cache range
+        cache.add_no_cache(0x3000..0x3004);
+        cache.dma_read_t(0x3000, &mut w).unwrap();
+        assert_eq!(w, 0x30303030);
+        assert_eq!(cache.stats().uncached, 1);
+
+        // 0x2000 and 0x4000 evict 0x1000, then 0x1000 evicts 0x2000
+        let mut buf = vec![];
+        cache.dma_read(0x1ff8, &mut buf, 16).unwrap();
+        assert_eq!(buf[..8], [0x9f; 8]);
+        assert_eq!(buf[8..], [0x20; 8]);
+        cache.dma_read_t(0x4000, &mut v).unwrap();
+        cache.dma_read_t(0x1000, &mut v).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 4,
+                evictions: 2,
+                uncached: 1,
+            }
+        );
+
+        cache.invalidate(0x1000..0x1001);
+        assert_eq!(cache.len(), 1);
+        drop(nettlp);
+        // 4 pages of 32 requests and an uncached request, and a write
+        assert_eq!(th.join().unwrap(), 4 * 32 + 1 + 1);
+        assert_eq!(reads.load(Ordering::Relaxed), 4 * 32 + 1);
+    }
+}
@@ -1,3 +1,9 @@
+use crate::message::MsgRouting;
+use crate::pci::Bdf;
+use crate::tlp::CplStatus;
+
+use std::ops::Range;
+
 #[derive(thiserror::Error, Debug)]
 #[non_exhaustive]
 pub enum Error {
@@ -7,8 +13,21 @@ pub enum Error {
     Timeout,
     #[error("invalid data response: {0}")]
     InvalidData(String),
-    #[error("invalid address for DMA: {0:#x}")]
+    #[error("invalid request length: {0} bytes")]
+    InvalidLength(usize),
+    #[error("invalid message routing: {0:?}")]
+    InvalidRouting(MsgRouting),
+    #[error("invalid address: {0:#x}")]
     InvalidAddress(u64),
+    #[error("invalid tags: {0:?}")]
+    InvalidTags(Range<u8>),
+    #[error("completion status {status} from {completer} (tag: {tag}, address: {addr:#x})")]
+    CompletionStatus {
+        status: CplStatus,
+        completer: Bdf,
+        tag: u8,
+        addr: u64,
+    },
     #[error("invalid PCI BDF string: {0}")]
     InvalidBDF(String),
 }
@@ -0,0 +1,169 @@
+// Ethernet/IPv4/UDP framing for transports that bypass the IP stack
+//
+// NetTLP runs on a dedicated point-to-point link, so the headers of the datagrams
+// are crafted and parsed here instead of going through the kernel network stack.
+
+use crate::error::Error;
+
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::os::unix::io::RawFd;
+use std::time::Instant;
+
+pub(crate) const ETH_HDR_SIZE: usize = 14;
+pub(crate) const IP_HDR_SIZE: usize = 20;
+pub(crate) const UDP_HDR_SIZE: usize = 8;
+/// Size of the headers built by `UdpFlow::headers()`
+pub(crate) const HDR_SIZE: usize = ETH_HDR_SIZE + IP_HDR_SIZE + UDP_HDR_SIZE;
+pub(crate) const ETH_P_IP: u16 = 0x0800;
+const IPPROTO_UDP: u8 = 17;
+
+/// A UDP flow between this host and the adapter
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct UdpFlow {
+    pub local_mac: [u8; 6],
+    pub remote_mac: [u8; 6],
+    pub local_addr: Ipv4Addr,
+    pub remote_addr: Ipv4Addr,
+    pub port: u16,
+}
+
+impl UdpFlow {
+    // Build Ethernet, IPv4 and UDP headers
+    pub fn headers(&self, ip_id: u16, payload_len: usize) -> [u8; HDR_SIZE] {
+        let mut h = [0u8; HDR_SIZE];
+
+        // Ethernet
+        h[0..6].copy_from_slice(&self.remote_mac);
+        h[6..12].copy_from_slice(&self.local_mac);
+        h[12..14].copy_from_slice(&ETH_P_IP.to_be_bytes());
+
+        // IPv4
+        let ip = &mut h[ETH_HDR_SIZE..ETH_HDR_SIZE + IP_HDR_SIZE];
+        let total_len = (IP_HDR_SIZE + UDP_HDR_SIZE + payload_len) as u16;
+        ip[0] = 0x45; // version 4, IHL 5
+        ip[2..4].copy_from_slice(&total_len.to_be_bytes());
+        ip[4..6].copy_from_slice(&ip_id.to_be_bytes());
+        ip[6] = 0x40; // don't fragment
+        ip[8] = 64; // TTL
+        ip[9] = IPPROTO_UDP;
+        ip[12..16].copy_from_slice(&self.local_addr.octets());
+        ip[16..20].copy_from_slice(&self.remote_addr.octets());
+        let csum = checksum(ip);
+        ip[10..12].copy_from_slice(&csum.to_be_bytes());
+
+        // UDP (checksum is optional for IPv4)
+        let udp = &mut h[ETH_HDR_SIZE + IP_HDR_SIZE..];
+        udp[0..2].copy_from_slice(&self.port.to_be_bytes());
+        udp[2..4].copy_from_slice(&self.port.to_be_bytes());
+        udp[4..6].copy_from_slice(&((UDP_HDR_SIZE + payload_len) as u16).to_be_bytes());
+
+        h
+    }
+
+    // Return the UDP payload if `frame` is a datagram from the adapter to this flow
+    pub fn parse<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        self.payload(frame, frame.len()).map(|range| &frame[range])
+    }
+
+    // Return the range of the UDP payload in a frame of `frame_len` bytes that starts with
+    // `headers`, if the frame is a datagram from the adapter to this flow.
+    // A frame whose IPv4 options do not fit in `headers` is not taken.
+    pub fn payload(&self, headers: &[u8], frame_len: usize) -> Option<Range<usize>> {
+        if headers.len() < HDR_SIZE
+            || frame_len < headers.len()
+            || headers[12..14] != ETH_P_IP.to_be_bytes()
+        {
+            return None;
+        }
+        let ip = &headers[ETH_HDR_SIZE..];
+        let ihl = ((ip[0] & 0xF) as usize) * 4;
+        let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+        let fragmented = u16::from_be_bytes([ip[6], ip[7]]) & 0x3FFF != 0;
+        if ip[0] >> 4 != 4
+            || ihl < IP_HDR_SIZE
+            || ihl + UDP_HDR_SIZE > ip.len()
+            || ip[9] != IPPROTO_UDP
+            || fragmented
+            || total_len > frame_len - ETH_HDR_SIZE
+            || total_len < ihl + UDP_HDR_SIZE
+            || ip[12..16] != self.remote_addr.octets()
+            || ip[16..20] != self.local_addr.octets()
+        {
+            return None;
+        }
+        let udp = &ip[ihl..ihl + UDP_HDR_SIZE];
+        let udp_len = u16::from_be_bytes([udp[4], udp[5]]) as usize;
+        if udp[2..4] != self.port.to_be_bytes()
+            || udp_len < UDP_HDR_SIZE
+            || udp_len > total_len - ihl
+        {
+            return None;
+        }
+        let start = ETH_HDR_SIZE + ihl;
+        Some(start + UDP_HDR_SIZE..start + udp_len)
+    }
+}
+
+// Interface index of `ifname`
+pub(crate) fn ifindex(ifname: &str) -> Result<u32, Error> {
+    let cname = std::ffi::CString::new(ifname)
+        .map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)))?;
+    let ifindex = unsafe { libc::if_nametoindex(cname.as_ptr()) };
+    if ifindex == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(ifindex)
+}
+
+pub(crate) fn read_mac(ifname: &str) -> Result<[u8; 6], Error> {
+    let path = format!("/sys/class/net/{}/address", ifname);
+    let s = std::fs::read_to_string(path)?;
+    let mut mac = [0u8; 6];
+    let octets: Vec<_> = s.trim().split(':').collect();
+    if octets.len() != 6 {
+        return Err(Error::InvalidData(format!(
+            "Invalid MAC address: {}",
+            s.trim()
+        )));
+    }
+    for (m, o) in mac.iter_mut().zip(octets) {
+        *m = u8::from_str_radix(o, 16)
+            .map_err(|_| Error::InvalidData(format!("Invalid MAC address: {}", s.trim())))?;
+    }
+    Ok(mac)
+}
+
+// Wait until `fd` becomes readable
+pub(crate) fn poll(fd: RawFd, deadline: Instant) -> Result<(), Error> {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    let ms = std::cmp::min(timeout.as_micros().div_ceil(1000), i32::MAX as u128) as libc::c_int;
+    let mut pfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let r = unsafe { libc::poll(&mut pfd, 1, ms) };
+    if r == 0 {
+        return Err(Error::Timeout);
+    }
+    if r < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+// Internet checksum (RFC 1071)
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -1,10 +1,49 @@
 #![doc = include_str!("../README.md")]
 #![warn(rust_2018_idioms)]
 
+// Code generated by libtlp-derive refers to `::libtlp`
+#[cfg(test)]
+extern crate self as libtlp;
+
+pub use crate::cache::{CacheStats, CachedNetTlp};
 pub use crate::error::Error;
-pub use crate::nettlp::{DmaDirection, NetTlp};
+pub use crate::nettlp::{AtomicOperand, CasOperand, DmaDirection, DmaReadRequest, NetTlp};
+#[cfg(all(target_os = "linux", feature = "af-packet"))]
+pub use crate::packet::PacketTransport;
+pub use crate::phys::{PhysPtr, PhysSlice};
+pub use crate::pool::{NetTlpPool, PooledNetTlp};
+pub use crate::stats::NetTlpStats;
+pub use crate::tlp::CplStatus;
+pub use crate::transport::{ChannelTransport, Transport, UdpTransport};
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub use crate::uring::{DmaCompletion, DmaRequest, UringEngine};
+#[cfg(all(target_os = "linux", feature = "xdp"))]
+pub use crate::xdp::XdpTransport;
+pub mod message;
+pub mod msi;
 pub mod pci;
+pub mod phys;
+
+// Declared first so that the macros are visible in the other modules
+#[macro_use]
+mod trace;
 
+mod cache;
 mod error;
+#[cfg(all(target_os = "linux", any(feature = "af-packet", feature = "xdp")))]
+mod ether;
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+mod mmsg;
 mod nettlp;
+#[cfg(all(target_os = "linux", feature = "af-packet"))]
+mod packet;
+mod pool;
+mod stats;
+#[cfg(test)]
+mod testutil;
 mod tlp;
+mod transport;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring;
+#[cfg(all(target_os = "linux", feature = "xdp"))]
+mod xdp;
@@ -0,0 +1,144 @@
+use crate::error::Error;
+use crate::pci;
+
+/// Legacy interrupt pin
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IntxPin {
+    A,
+    B,
+    C,
+    D,
+}
+
+/// Routing subfield (r[2:0]) of a message TLP type
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MsgRouting {
+    /// Routed to Root Complex
+    RootComplex = 0b000,
+    /// Routed by Address
+    Address = 0b001,
+    /// Routed by ID
+    Id = 0b010,
+    /// Broadcast from Root Complex
+    Broadcast = 0b011,
+    /// Local - Terminate at Receiver
+    Local = 0b100,
+    /// Gathered and routed to Root Complex
+    Gather = 0b101,
+}
+
+/// Message request
+///
+/// Routing of the messages other than vendor-defined ones is fixed by the specification.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Message {
+    /// Assert_INTx
+    AssertIntx(IntxPin),
+    /// Deassert_INTx
+    DeassertIntx(IntxPin),
+    /// PM_PME
+    PmPme,
+    /// Set_Slot_Power_Limit (`value` x 10^-`scale` W)
+    SetSlotPowerLimit { value: u8, scale: u8 },
+    /// ERR_COR
+    ErrCor,
+    /// ERR_NONFATAL
+    ErrNonFatal,
+    /// ERR_FATAL
+    ErrFatal,
+    /// Vendor_Defined Type 0 (`type1 == false`) or Type 1 (`type1 == true`)
+    ///
+    /// `routing` must be [`MsgRouting::RootComplex`], [`MsgRouting::Id`],
+    /// [`MsgRouting::Broadcast`] or [`MsgRouting::Local`].
+    /// `target` is used only when `routing` is [`MsgRouting::Id`].
+    /// The length of `payload` must be a multiple of 4 bytes and at most 4096 bytes.
+    VendorDefined {
+        type1: bool,
+        routing: MsgRouting,
+        target: pci::Bdf,
+        vendor_id: u16,
+        vendor_data: u32,
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    /// Routing subfield used for this message
+    pub fn routing(&self) -> MsgRouting {
+        match self {
+            Message::AssertIntx(_) | Message::DeassertIntx(_) => MsgRouting::Local,
+            Message::SetSlotPowerLimit { .. } => MsgRouting::Local,
+            Message::PmPme | Message::ErrCor | Message::ErrNonFatal | Message::ErrFatal => {
+                MsgRouting::RootComplex
+            }
+            Message::VendorDefined { routing, .. } => *routing,
+        }
+    }
+
+    /// Message code
+    pub fn code(&self) -> u8 {
+        let pin = |p: &IntxPin| *p as u8;
+        match self {
+            Message::AssertIntx(p) => 0x20 + pin(p),
+            Message::DeassertIntx(p) => 0x24 + pin(p),
+            Message::PmPme => 0x18,
+            Message::SetSlotPowerLimit { .. } => 0x50,
+            Message::ErrCor => 0x30,
+            Message::ErrNonFatal => 0x31,
+            Message::ErrFatal => 0x33,
+            Message::VendorDefined { type1: false, .. } => 0x7E,
+            Message::VendorDefined { type1: true, .. } => 0x7F,
+        }
+    }
+
+    /// Maximum payload size of a message (1024 DW)
+    const MAX_PAYLOAD: usize = 0x1000;
+
+    // Check if the message can be encoded in a TLP
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if let Message::VendorDefined {
+            routing, payload, ..
+        } = self
+        {
+            if matches!(routing, MsgRouting::Address | MsgRouting::Gather) {
+                return Err(Error::InvalidRouting(*routing));
+            }
+            if !payload.len().is_multiple_of(4) || payload.len() > Message::MAX_PAYLOAD {
+                return Err(Error::InvalidLength(payload.len()));
+            }
+        }
+        Ok(())
+    }
+
+    // 3rd and 4th DW of the message header
+    pub(crate) fn header_dws(&self) -> (u32, u32) {
+        match self {
+            Message::VendorDefined {
+                routing,
+                target,
+                vendor_id,
+                vendor_data,
+                ..
+            } => {
+                let id = if *routing == MsgRouting::Id {
+                    target.to_u16()
+                } else {
+                    0
+                };
+                (((id as u32) << 16) | (*vendor_id as u32), *vendor_data)
+            }
+            _ => (0, 0),
+        }
+    }
+
+    pub(crate) fn payload(&self) -> Option<Vec<u8>> {
+        match self {
+            Message::SetSlotPowerLimit { value, scale } => {
+                let dw = (*value as u32) | (((*scale & 0x3) as u32) << 8);
+                Some(dw.to_le_bytes().to_vec())
+            }
+            Message::VendorDefined { payload, .. } if !payload.is_empty() => Some(payload.clone()),
+            _ => None,
+        }
+    }
+}
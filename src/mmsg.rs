@@ -0,0 +1,73 @@
+// sendmmsg(2) / recvmmsg(2) wrappers to batch datagrams in one system call
+
+use std::io;
+use std::net::UdpSocket;
+use std::os::unix::io::AsRawFd;
+
+/// Send all `packets` through `socket`
+pub(crate) fn send(socket: &UdpSocket, packets: &[&[u8]]) -> io::Result<()> {
+    let mut iovs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|p| libc::iovec {
+            iov_base: p.as_ptr() as *mut _,
+            iov_len: p.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovs.iter_mut().map(mmsghdr).collect();
+
+    let mut sent = 0;
+    while sent < msgs.len() {
+        let n = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs[sent..].as_mut_ptr(),
+                (msgs.len() - sent) as libc::c_uint,
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        sent += n as usize;
+    }
+    Ok(())
+}
+
+/// Receive datagrams into `bufs` and return the size of each received datagram
+///
+/// This blocks until at least one datagram arrives (or the read timeout of `socket` expires),
+/// and then returns whatever has been already queued.
+pub(crate) fn recv(socket: &UdpSocket, bufs: &mut [Vec<u8>]) -> io::Result<Vec<usize>> {
+    let mut iovs: Vec<libc::iovec> = bufs
+        .iter_mut()
+        .map(|b| libc::iovec {
+            iov_base: b.as_mut_ptr() as *mut _,
+            iov_len: b.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovs.iter_mut().map(mmsghdr).collect();
+
+    let n = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            libc::MSG_WAITFORONE,
+            std::ptr::null_mut(),
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(msgs[..n as usize]
+        .iter()
+        .map(|m| m.msg_len as usize)
+        .collect())
+}
+
+fn mmsghdr(iov: &mut libc::iovec) -> libc::mmsghdr {
+    let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+    msg.msg_hdr.msg_iov = iov;
+    msg.msg_hdr.msg_iovlen = 1;
+    msg
+}
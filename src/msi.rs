@@ -0,0 +1,143 @@
+use crate::error::Error;
+use crate::nettlp::NetTlp;
+
+use zerocopy::{AsBytes, FromBytes};
+
+/// MSI-X table entry
+///
+/// +---------------+---------------+---------------+---------------+
+/// |                      Message Address (low)                    |
+/// +---------------+---------------+---------------+---------------+
+/// |                      Message Address (high)                   |
+/// +---------------+---------------+---------------+---------------+
+/// |                          Message Data                         |
+/// +---------------+---------------+---------------+---------------+
+/// |                         Vector Control                      |M|
+/// +---------------+---------------+---------------+---------------+
+///
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, FromBytes, AsBytes)]
+pub struct MsixEntry {
+    pub msg_addr_lo: u32,
+    pub msg_addr_hi: u32,
+    pub msg_data: u32,
+    pub vector_ctrl: u32,
+}
+
+impl MsixEntry {
+    const MSIX_VECTOR_CTRL_MASK: u32 = 0x1;
+
+    /// Message address
+    pub fn addr(&self) -> u64 {
+        ((self.msg_addr_hi as u64) << 32) | (self.msg_addr_lo as u64)
+    }
+
+    /// Whether the vector is masked
+    pub fn is_masked(&self) -> bool {
+        self.vector_ctrl & MsixEntry::MSIX_VECTOR_CTRL_MASK != 0
+    }
+}
+
+impl NetTlp {
+    /// Read `n` entries of the MSI-X table located at `table_addr`
+    pub fn read_msix_table(&self, table_addr: u64, n: usize) -> Result<Vec<MsixEntry>, Error> {
+        let mut entries = vec![MsixEntry::default(); n];
+        let len = std::mem::size_of::<MsixEntry>() * n;
+        self.dma_read(table_addr, &mut entries.as_bytes_mut(), len)?;
+        Ok(entries)
+    }
+
+    /// Raise MSI-X interrupt `vector` of the table located at `table_addr`
+    ///
+    /// The table entry is read every time so that the latest mask bit is respected.
+    /// Returns `false` without sending the interrupt if the vector is masked.
+    ///
+    /// The Pending Bit Array is not updated: a masked interrupt is dropped, not left pending,
+    /// because unmasking the vector cannot be observed to send it later.
+    /// Raise the interrupt again after the vector is unmasked if it must not be lost.
+    pub fn msix_raise(&self, table_addr: u64, vector: u16) -> Result<bool, Error> {
+        let addr = table_addr + (vector as u64) * std::mem::size_of::<MsixEntry>() as u64;
+        let mut entry = MsixEntry::default();
+        self.dma_read_t(addr, &mut entry)?;
+        self.msix_raise_entry(&entry)
+    }
+
+    /// Raise MSI-X interrupt described by `entry`
+    ///
+    /// Returns `false` without sending the interrupt if the vector is masked.
+    /// As with `msix_raise()`, the Pending Bit Array is not updated.
+    pub fn msix_raise_entry(&self, entry: &MsixEntry) -> Result<bool, Error> {
+        if entry.is_masked() {
+            return Ok(false);
+        }
+        self.dma_write_t(entry.addr(), entry.msg_data)?;
+        Ok(true)
+    }
+
+    /// Raise MSI interrupt by writing `data` to `addr`
+    ///
+    /// For multiple message MSI, the vector number should be set in the lower bits of `data`.
+    pub fn msi_raise(&self, addr: u64, data: u16) -> Result<(), Error> {
+        // MSI data is a DW write whose upper 16 bits are zero
+        self.dma_write_t(addr, data as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci;
+    use crate::testutil::{respond, Mrd};
+    use crate::transport::ChannelTransport;
+
+    #[test]
+    fn msix_entry() {
+        let bytes: [u8; 16] = [
+            0x00, 0x10, 0xe0, 0xfe, 0, 0, 0, 0, 0x41, 0, 0, 0, 0x01, 0, 0, 0,
+        ];
+        let entry = MsixEntry::read_from(&bytes[..]).unwrap();
+        assert_eq!(entry.addr(), 0xfee0_1000);
+        assert_eq!(entry.msg_data, 0x41);
+        assert!(entry.is_masked());
+    }
+
+    #[test]
+    fn msix_raise() {
+        let (transport, adapter) = ChannelTransport::pair();
+        let nettlp = NetTlp::with_transport(pci::Bdf::new(1, 0, 0), 0, 512, transport);
+        let table = [
+            MsixEntry {
+                msg_addr_lo: 0xfee0_1000,
+                msg_addr_hi: 0,
+                msg_data: 0x41,
+                vector_ctrl: 0,
+            },
+            MsixEntry {
+                msg_addr_lo: 0xfee0_2000,
+                msg_addr_hi: 0,
+                msg_data: 0x42,
+                vector_ctrl: 1,
+            },
+        ];
+
+        // Pretend to be the adapter: reply the table at 0x8000, and check that the only write
+        // is the message data of vector 0 to its message address
+        let th = respond(adapter, move |tlp| {
+            if let Some(mrd) = Mrd::parse(tlp) {
+                let bytes = table.as_bytes();
+                return vec![mrd.reply(|addr| bytes[(addr - 0x8000) as usize])];
+            }
+            assert_eq!(tlp[0], 0x40);
+            assert_eq!(tlp[8..12], 0xfee0_1000u32.to_be_bytes());
+            assert_eq!(tlp[12..], 0x41u32.to_le_bytes());
+            vec![]
+        });
+
+        assert!(nettlp.msix_raise(0x8000, 0).unwrap());
+        assert!(!nettlp.msix_raise(0x8000, 1).unwrap());
+        assert!(!nettlp.msix_raise_entry(&table[1]).unwrap());
+        drop(nettlp);
+        // 2 reads and a write
+        assert_eq!(th.join().unwrap(), 3);
+    }
+}
@@ -1,19 +1,22 @@
 use crate::error::Error;
+use crate::message;
 use crate::pci;
+use crate::stats::{Counters, NetTlpStats};
 use crate::tlp;
+use crate::transport::{Transport, UdpTransport};
 
 use std::net::Ipv4Addr;
 use std::net::UdpSocket;
+use std::ops::Range;
+use std::time::{Duration, Instant};
 
 use bytes::buf::UninitSlice;
 use bytes::BufMut;
 use zerocopy::{AsBytes, FromBytes};
 
-const EAGAIN: i32 = 11;
-
-#[repr(packed)]
+#[repr(C, packed)]
 #[derive(Clone, Copy, Debug, AsBytes)]
-struct NetTlpHdr {
+pub(crate) struct NetTlpHdr {
     // NOTE: The header contants are not used for now
     /// Sequence number
     #[allow(dead_code)]
@@ -32,6 +35,50 @@ impl NetTlpHdr {
     }
 }
 
+/// Operand types of FetchAdd and Swap AtomicOps
+pub trait AtomicOperand: Copy + FromBytes + AsBytes {}
+
+impl AtomicOperand for u32 {}
+impl AtomicOperand for u64 {}
+
+/// Operand types of CAS AtomicOp
+pub trait CasOperand: Copy + FromBytes + AsBytes {}
+
+impl CasOperand for u32 {}
+impl CasOperand for u64 {}
+impl CasOperand for u128 {}
+
+/// A read request of `NetTlp::dma_read_batch`
+#[derive(Debug)]
+pub struct DmaReadRequest<'a> {
+    /// Physical address to read
+    pub addr: u64,
+    /// Buffer to store the data (`buf.len()` bytes are read)
+    pub buf: &'a mut [u8],
+}
+
+// A memory read request TLP in flight
+#[derive(Debug)]
+pub(crate) struct InflightMrd {
+    /// Tag of the TLP, which is not shared with other TLPs in flight
+    pub tag: u8,
+    /// Index of the request
+    pub req: usize,
+    /// Offset in the buffer of the request
+    pub offset: usize,
+    pub addr: u64,
+    pub len: usize,
+    pub received: usize,
+}
+
+impl InflightMrd {
+    // Whether a completion of the tag is the next one of the request
+    fn is_next(&self, lowaddr: u8, count: u16) -> bool {
+        let next = self.addr + self.received as u64;
+        (next & 0x7F) as u8 == lowaddr && (self.len - self.received) as u16 == count
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum DmaDirection {
     DmaIssuedByLibTLP,
@@ -40,27 +87,22 @@ pub enum DmaDirection {
 
 #[derive(Debug)]
 pub struct NetTlp {
-    pub remote_addr: Ipv4Addr,
-    pub local_addr: Ipv4Addr,
     pub requester: pci::Bdf,
+    /// Tag of requests, and the first tag of `dma_read_batch()`
     pub tag: u8,
+    /// Number of tags from `tag`
+    ntags: usize,
     pub mrrs: usize,
-    pub dir: DmaDirection,
-    pub socket: UdpSocket,
+    transport: Box<dyn Transport>,
+    pub(crate) stats: Counters,
 }
 
 impl NetTlp {
-    /* TODO: implement
-    /// Port for messaging API
-    const NETTLP_MSG_PORT: u16 = 0x2FFF; // 12287
-    */
-    /// Base port for DmaIssuedByLibTLP mode
-    const NETTLP_LIBTLP_PORT_BASE: u16 = 0x3000;
-    /// Base port for DmaIssuedByAdapter mode
-    const NETTLP_ADAPTER_PORT_BASE: u16 = 0x4000;
     /// The timeout value of receiving completion TLPs
-    const LIBTLP_CPL_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+    pub(crate) const LIBTLP_CPL_TIMEOUT: std::time::Duration =
+        std::time::Duration::from_millis(500);
 
+    /// Create a handle that talks with the adapter over UDP
     pub fn new(
         bdf: pci::Bdf,
         local_addr: Ipv4Addr,
@@ -69,25 +111,100 @@ impl NetTlp {
         mrrs: usize,
         dir: DmaDirection,
     ) -> Result<Self, Error> {
-        let requester = bdf;
-        let port = match dir {
-            DmaDirection::DmaIssuedByLibTLP => NetTlp::NETTLP_LIBTLP_PORT_BASE + (tag as u16),
-            DmaDirection::DmaIssuedByAdapter => {
-                NetTlp::NETTLP_ADAPTER_PORT_BASE + ((tag & 0x0F) as u16)
-            }
-        };
-        let socket = UdpSocket::bind((local_addr, port))?;
-        socket.set_read_timeout(Some(NetTlp::LIBTLP_CPL_TIMEOUT))?;
-        socket.connect((remote_addr, port))?;
-        Ok(NetTlp {
-            remote_addr,
-            local_addr,
-            requester,
+        let transport = UdpTransport::new(local_addr, remote_addr, tag, dir)?;
+        Ok(NetTlp::with_transport(bdf, tag, mrrs, transport))
+    }
+
+    /// Create a handle that talks with the adapter over UDP with `tags`
+    ///
+    /// `dma_read_batch()` keeps a read request in flight for each tag,
+    /// and the other requests use the first tag.
+    /// Returns `Error::InvalidTags` if the completions of `tags` cannot be told apart
+    /// (see `UdpTransport::with_tags()`).
+    pub fn with_tags(
+        bdf: pci::Bdf,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        tags: Range<u8>,
+        mrrs: usize,
+        dir: DmaDirection,
+    ) -> Result<Self, Error> {
+        let transport = UdpTransport::with_tags(local_addr, remote_addr, tags.clone(), dir)?;
+        Ok(NetTlp::with_transport_tags(bdf, tags, mrrs, transport))
+    }
+
+    /// Create a handle that uses `transport`
+    pub fn with_transport<T: Transport + 'static>(
+        bdf: pci::Bdf,
+        tag: u8,
+        mrrs: usize,
+        transport: T,
+    ) -> Self {
+        NetTlp {
+            requester: bdf,
             tag,
+            ntags: 1,
             mrrs,
-            dir,
-            socket,
-        })
+            transport: Box::new(transport),
+            stats: Counters::default(),
+        }
+    }
+
+    /// Create a handle that uses `tags` and `transport`
+    ///
+    /// `transport` must deliver the completions of all `tags`.
+    pub fn with_transport_tags<T: Transport + 'static>(
+        bdf: pci::Bdf,
+        tags: Range<u8>,
+        mrrs: usize,
+        transport: T,
+    ) -> Self {
+        assert!(!tags.is_empty(), "no tags are given");
+        NetTlp {
+            ntags: tags.len(),
+            ..NetTlp::with_transport(bdf, tags.start, mrrs, transport)
+        }
+    }
+
+    /// Tags of this handle
+    pub fn tags(&self) -> impl DoubleEndedIterator<Item = u8> {
+        let tag = self.tag;
+        (0..self.ntags).map(move |i| tag + i as u8)
+    }
+
+    /// Transport of this handle
+    pub fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+
+    /// Address of the adapter if the handle talks with it over UDP
+    pub fn remote_addr(&self) -> Option<Ipv4Addr> {
+        self.transport.as_udp().map(|udp| udp.remote_addr)
+    }
+
+    /// Local address if the handle talks with the adapter over UDP
+    pub fn local_addr(&self) -> Option<Ipv4Addr> {
+        self.transport.as_udp().map(|udp| udp.local_addr)
+    }
+
+    /// DMA direction if the handle talks with the adapter over UDP
+    pub fn dir(&self) -> Option<DmaDirection> {
+        self.transport.as_udp().map(|udp| udp.dir)
+    }
+
+    /// UDP socket if the handle talks with the adapter over UDP
+    pub fn socket(&self) -> Option<&UdpSocket> {
+        self.transport.as_udp().map(|udp| udp.socket())
+    }
+
+    /// Statistics since the creation or the last `reset_stats()`
+    pub fn stats(&self) -> NetTlpStats {
+        self.stats.snapshot()
+    }
+
+    /// Reset the statistics
+    pub fn reset_stats(&self) {
+        self.stats.reset();
     }
 
     /// Read `sizeof(T)` bytes into `t` from a physical addr
@@ -106,12 +223,18 @@ impl NetTlp {
     /// Several read requests are made when:
     ///   1. Read size is larger than MRRS
     ///   2. A request crosses 4k boundary
+    ///
+    /// A read of 0 bytes is a zero-length read (see `flush()`).
     // There is no BufMut::len(), and BufMut::remaining_mut() is not the buffer length.
     // It is the length that can be written from the current position.
     // For Vec<u8>, BufMut::remaining_mut() is isize::MAX - buf.len().
     // Therefore the function takes `len` as an additional argument.
     pub fn dma_read<T: BufMut>(&self, addr: u64, buf: &mut T, len: usize) -> Result<(), Error> {
         assert!(len <= buf.remaining_mut());
+        if len == 0 {
+            return self.flush(addr);
+        }
+        dma_span!("dma_read", addr = format_args!("{:#x}", addr), len);
         let total_len = len;
         let mut p = addr;
         let mut received = 0;
@@ -122,8 +245,9 @@ impl NetTlp {
             use std::cmp::min;
             let len = min(min(min(remain, self.mrrs), max_len), chunk_len);
 
+            self.stats.dma_tlp(true, received == 0);
             self.send_mrd(p, len)?;
-            self.recv_cpld(p, &mut buf.chunk_mut()[..len])?;
+            self.recv_cpld(p, &mut buf.chunk_mut()[..len], true)?;
             received += len;
             p += len as u64;
             unsafe {
@@ -136,6 +260,135 @@ impl NetTlp {
         Ok(())
     }
 
+    /// Read all `reqs`, keeping several read requests in flight
+    ///
+    /// Each request is split in the same way as `dma_read()`.
+    /// A read request TLP is kept in flight for each tag of this handle
+    /// (see `NetTlp::with_tags()`), so with a single tag the TLPs are sent one by one.
+    /// Nothing is sent for requests with an empty buffer.
+    /// With the `mmsg` feature on Linux, requests and completions are batched
+    /// with sendmmsg(2) and recvmmsg(2).
+    /// Completions that match no request in flight, such as late completions of an earlier
+    /// call, are counted as stale and dropped.
+    /// On error, completions that have already arrived are discarded.
+    pub fn dma_read_batch(&self, reqs: &mut [DmaReadRequest<'_>]) -> Result<(), Error> {
+        dma_span!("dma_read_batch", reqs = reqs.len());
+        let r = self.read_batch(reqs);
+        if r.is_err() {
+            self.discard_pending();
+        }
+        r
+    }
+
+    fn read_batch(&self, reqs: &mut [DmaReadRequest<'_>]) -> Result<(), Error> {
+        use std::cmp::min;
+        use std::collections::VecDeque;
+
+        let mut pending = VecDeque::new();
+        for (i, req) in reqs.iter().enumerate() {
+            let mut offset = 0;
+            while offset < req.buf.len() {
+                let addr = req.addr + offset as u64;
+                let max_len = 0x1000 - (addr & 0xFFF) as usize;
+                let len = min(min(req.buf.len() - offset, self.mrrs), max_len);
+                self.stats.dma_tlp(true, offset == 0);
+                pending.push_back(InflightMrd {
+                    tag: 0,
+                    req: i,
+                    offset,
+                    addr,
+                    len,
+                    received: 0,
+                });
+                offset += len;
+            }
+        }
+
+        let nh_size = std::mem::size_of::<NetTlpHdr>();
+        let cpl_size = std::mem::size_of::<tlp::TlpCplHdr>();
+        // A completion contains at most 4k bytes (+ extra bytes for non DW-aligned data)
+        let bufsize = nh_size + cpl_size + min(self.mrrs, 0x1000) + 8;
+        let mut recv_bufs = vec![vec![0; bufsize]; min(self.ntags, pending.len())];
+        let mut free_tags: Vec<u8> = self.tags().rev().collect();
+        let mut inflight: Vec<InflightMrd> = Vec::with_capacity(self.ntags);
+        let mut deadline = Instant::now() + NetTlp::LIBTLP_CPL_TIMEOUT;
+        loop {
+            let mut packets = vec![];
+            while !pending.is_empty() {
+                let Some(tag) = free_tags.pop() else {
+                    break;
+                };
+                let mut m = pending.pop_front().unwrap();
+                m.tag = tag;
+                packets.push(self.mr_packet(tag, m.addr, m.len, tlp::TlpType::Mrd, None)?);
+                inflight.push(m);
+            }
+            if !packets.is_empty() {
+                let packets: Vec<&[u8]> = packets.iter().map(|p| &p[..]).collect();
+                self.transport.send_batch(&packets)?;
+            }
+            if inflight.is_empty() {
+                break;
+            }
+
+            let sizes = self
+                .transport
+                .recv_batch(&mut recv_bufs, self.remaining(deadline)?)
+                .map_err(|e| self.stats.error(e))?;
+            for (recv_buf, n) in recv_bufs.iter().zip(sizes) {
+                let packet = &recv_buf[..n];
+                let cpld = self.parse_cpl(packet)?;
+
+                let Some(i) = find_inflight(&inflight, &cpld) else {
+                    self.stale_cpl(&cpld);
+                    continue;
+                };
+                deadline = Instant::now() + NetTlp::LIBTLP_CPL_TIMEOUT;
+                let m = &mut inflight[i];
+                self.check_cpl(m.addr + m.received as u64, &cpld, true)?;
+
+                let data = cpld_data(packet, &cpld).map_err(|e| self.stats.error(e))?;
+                let size = data.len();
+                if size > m.len - m.received {
+                    return Err(self.stats.error(Error::InvalidData(format!(
+                        "TLP payload size is larger than the requested size: {} > {}",
+                        size,
+                        m.len - m.received
+                    ))));
+                }
+                Counters::add(&self.stats.bytes_in, size);
+
+                let buf_start = m.offset + m.received;
+                reqs[m.req].buf[buf_start..buf_start + size].copy_from_slice(data);
+                m.received += size;
+                if m.received == m.len {
+                    free_tags.push(m.tag);
+                    inflight.remove(i);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Zero-length read of `addr`
+    ///
+    /// A zero-length read is a 1 DW memory read request with no bytes enabled.
+    /// Since a read completion does not pass posted writes, it returns after
+    /// the preceding DMA writes of this handle to the same completer have been done.
+    pub fn flush(&self, addr: u64) -> Result<(), Error> {
+        dma_span!("flush", addr = format_args!("{:#x}", addr));
+        self.stats.dma_tlp(true, true);
+        self.send_mrd(addr, 0)?;
+        // The completion carries 1 DW of undefined data.
+        // The byte count is 1 as the spec requires, or 4 from a completer that counts the DW.
+        let mut recv_buf = [0u8; 64];
+        let lowaddr = (addr & 0x7C) as u8;
+        let (_, cpl) = self.recv_cpl_hdr(&mut recv_buf, |l, count| {
+            l == lowaddr && matches!(count, 1 | 4)
+        })?;
+        self.check_cpl(addr, &cpl, true)
+    }
+
     fn send_mrd(&self, addr: u64, len: usize) -> Result<(), Error> {
         self.send_mr(addr, len, tlp::TlpType::Mrd, None)
     }
@@ -152,6 +405,37 @@ impl NetTlp {
         t: tlp::TlpType,
         data: Option<&[u8]>,
     ) -> Result<(), Error> {
+        let packet = self.mr_packet(self.tag, addr, len, t, data)?;
+        self.transport.send(&packet)?;
+        Ok(())
+    }
+
+    // Build a memory (reqd|write) request TLP of `tag` with a nettlp header
+    pub(crate) fn mr_packet(
+        &self,
+        tag: u8,
+        addr: u64,
+        len: usize,
+        t: tlp::TlpType,
+        data: Option<&[u8]>,
+    ) -> Result<bytes::BytesMut, Error> {
+        trace!(
+            tlp = ?t,
+            addr = format_args!("{:#x}", addr),
+            len,
+            tag,
+            "request TLP"
+        );
+        let counter = match t {
+            tlp::TlpType::Mrd => &self.stats.mrd,
+            tlp::TlpType::Mwr => &self.stats.mwr,
+            tlp::TlpType::IoRd => &self.stats.io_rd,
+            tlp::TlpType::IoWr => &self.stats.io_wr,
+            _ => &self.stats.atomic,
+        };
+        Counters::add(counter, 1);
+        Counters::add(&self.stats.bytes_out, data.map_or(0, |d| d.len()));
+
         let nh = NetTlpHdr::new();
         let mut packet = bytes::BytesMut::new();
 
@@ -161,10 +445,10 @@ impl NetTlp {
         // TLP header
         // Separte function calls are necessary to expolit generics
         if addr <= u32::MAX as u64 {
-            let mh = tlp::TlpMrHdr::new(t, self.requester, self.tag, addr as u32, len);
+            let mh = tlp::TlpMrHdr::new(t, self.requester, tag, addr as u32, len)?;
             packet.extend_from_slice(mh.as_bytes());
         } else {
-            let mh = tlp::TlpMrHdr::new(t, self.requester, self.tag, addr, len);
+            let mh = tlp::TlpMrHdr::new(t, self.requester, tag, addr, len)?;
             packet.extend_from_slice(mh.as_bytes());
         };
 
@@ -173,17 +457,106 @@ impl NetTlp {
             packet.extend_from_slice(data.as_bytes());
         }
 
-        self.socket.send(&packet)?;
+        Ok(packet)
+    }
+
+    /// Send a message request
+    ///
+    /// Returns `Error::InvalidRouting` or `Error::InvalidLength` if a vendor-defined message
+    /// has a routing or a payload that cannot be encoded.
+    pub fn send_message(&self, msg: &message::Message) -> Result<(), Error> {
+        msg.check()?;
+        let nh = NetTlpHdr::new();
+        let payload = msg.payload();
+        let payload_len = payload.as_ref().map_or(0, |p| p.len());
+        let mut packet = bytes::BytesMut::new();
+
+        packet.extend_from_slice(nh.as_bytes());
+        let mh = tlp::TlpMsgHdr::new(msg, self.requester, self.tag, payload_len);
+        packet.extend_from_slice(mh.as_bytes());
+        if let Some(payload) = payload {
+            packet.extend_from_slice(&payload);
+        }
+
+        trace!(?msg, "message TLP");
+        self.transport.send(&packet)?;
+        Counters::add(&self.stats.msg, 1);
+        Counters::add(&self.stats.bytes_out, payload_len);
         Ok(())
     }
 
+    // Discard datagrams that have been already received
+    pub(crate) fn discard_pending(&self) {
+        self.transport.discard_pending();
+    }
+
+    // Receive a datagram into `recv_buf` and parse its completion header.
+    // Completions of other tags, or whose lower address and byte count are not those of
+    // the request (see `is_reply`), are dropped.
+    fn recv_cpl_hdr(
+        &self,
+        recv_buf: &mut [u8],
+        is_reply: impl Fn(u8, u16) -> bool,
+    ) -> Result<(usize, tlp::TlpCplHdr), Error> {
+        let deadline = Instant::now() + NetTlp::LIBTLP_CPL_TIMEOUT;
+        loop {
+            let n = self
+                .transport
+                .recv(recv_buf, self.remaining(deadline)?)
+                .map_err(|e| self.stats.error(e))?;
+            let cpl = self.parse_cpl(&recv_buf[..n])?;
+            if cpl.tag == self.tag && is_reply(cpl.lowaddr & 0x7F, cpl.count()) {
+                return Ok((n, cpl));
+            }
+            self.stale_cpl(&cpl);
+        }
+    }
+
+    // Time left until `deadline` to wait for a completion
+    fn remaining(&self, deadline: Instant) -> Result<Duration, Error> {
+        match deadline.checked_duration_since(Instant::now()) {
+            Some(timeout) if !timeout.is_zero() => Ok(timeout),
+            _ => Err(self.stats.error(Error::Timeout)),
+        }
+    }
+
+    // Parse the completion header of a received datagram
+    pub(crate) fn parse_cpl(&self, packet: &[u8]) -> Result<tlp::TlpCplHdr, Error> {
+        let cpl = parse_cpl_hdr(packet).map_err(|e| self.stats.error(e))?;
+        trace!(
+            status = %cpl.status(),
+            completer = %cpl.completer(),
+            tag = { cpl.tag },
+            lowaddr = format_args!("{:#x}", { cpl.lowaddr }),
+            count = cpl.count(),
+            length = cpl.length(),
+            "completion TLP"
+        );
+        Counters::add(&self.stats.completions, 1);
+        Ok(cpl)
+    }
+
+    // Count a completion that does not match the outstanding requests, which is dropped
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn stale_cpl(&self, cpl: &tlp::TlpCplHdr) {
+        Counters::add(&self.stats.stale, 1);
+        warn!(
+            tag = { cpl.tag },
+            lowaddr = format_args!("{:#x}", { cpl.lowaddr }),
+            count = cpl.count(),
+            "stale completion"
+        );
+    }
+
     // Receive completion with data TLP(s)
     // Note: It is possible to get several completion TLPs for one request
-    // TODO: zero-copy
-    fn recv_cpld(&self, addr: u64, buf: &mut UninitSlice) -> Result<(), Error> {
+    // Completions of other tags or other requests are dropped. The lower address is checked
+    // only for a memory read (`mem_read`), as it is reserved for AtomicOps.
+    fn recv_cpld(&self, addr: u64, buf: &mut UninitSlice, mem_read: bool) -> Result<(), Error> {
         let nh_size = std::mem::size_of::<NetTlpHdr>();
         let cpl_size = std::mem::size_of::<tlp::TlpCplHdr>();
-        // Extra bytes are for non DW-aligned data
+        // Bytes before the first valid byte are stored in the header buffer
+        // so that the payload lands in `buf` directly.
         // For exmaple, when reading 7 bytes from 0x3,
         // the completion TLP contains 3*4 bytes data
         //
@@ -191,75 +564,56 @@ impl NetTlp {
         //               |3|2|1|0| |3|2|1|0| |3|2|1|0|
         //  valid data:         x   x x x x   x x
         //
-        let etra_bytes = 6; // just enough size
-        let bufsize = nh_size + cpl_size + buf.len() + etra_bytes;
-        let mut recv_buf = vec![0; bufsize];
+        // Trailing invalid bytes are truncated at the end of `buf`
+        // or overwritten by the next completion.
+        let mut hdr = [0u8; 32];
         let mut received = 0;
+        let mut deadline = Instant::now() + NetTlp::LIBTLP_CPL_TIMEOUT;
         loop {
-            let n = self.socket.recv(&mut recv_buf).map_err(|e| {
-                if errno::errno().0 == EAGAIN {
-                    Error::Timeout
-                } else {
-                    Error::from(e)
-                }
-            })?;
-
-            if n < nh_size + cpl_size {
-                return Err(Error::InvalidData(format!(
-                    "Datagram size is less than TLP header size: {} < {}",
-                    n,
-                    nh_size + cpl_size
-                )));
-            }
+            let next = addr + received as u64;
+            let offset = (next & 0x3) as usize;
+            let hdr_len = nh_size + cpl_size + offset;
+            let n = self
+                .transport
+                .recv_split(
+                    &mut hdr[..hdr_len],
+                    &mut buf[received..],
+                    self.remaining(deadline)?,
+                )
+                .map_err(|e| self.stats.error(e))?;
+            let cpld = self.parse_cpl(&hdr[..std::cmp::min(n, hdr_len)])?;
 
-            let cpld: tlp::TlpCplHdr =
-                unsafe { std::ptr::read(recv_buf.as_ptr().add(nh_size) as *const _) };
-
-            if !cpld.is_completion_with_data() {
-                if cpld.is_completion() {
-                    return Err(Error::InvalidAddress(addr));
-                } else {
-                    return Err(Error::InvalidData(format!(
-                        "Invalid format type: {:#010b}",
-                        cpld.fmt_type
-                    )));
-                };
-            }
-            if !cpld.is_valid_status() {
-                return Err(Error::InvalidData(format!(
-                    "Invalid status: {:#b}",
-                    cpld.stcnt.to_be()
-                )));
+            // The byte count is the number of the remaining bytes including this completion.
+            // A completion of another request, even an unsuccessful one, is not taken.
+            let buf_len = buf[received..].len();
+            let lowaddr = if mem_read { next & 0x7F } else { offset as u64 };
+            if cpld.tag != self.tag
+                || (cpld.lowaddr & 0x7F) as u64 != lowaddr
+                || cpld.count() as usize != buf_len
+            {
+                self.stale_cpl(&cpld);
+                continue;
             }
 
-            let offset = (cpld.lowaddr & 0x3) as usize;
-            let start = nh_size + cpl_size + offset;
-            let end = if cpld.count() <= cpld.length() * 4 {
-                start + (cpld.count() as usize)
-            } else {
-                start + (cpld.length() as usize) * 4 - offset
-            };
-            let size = end - start;
-            let buf_start = received;
-            let buf_end = received + size;
-            let buf_len = buf[buf_start..].len();
-
-            if size > (n - (nh_size + cpl_size)) {
-                dbg!("Corrupted TLP?", n, nh_size, cpl_size, size, cpld);
-                return Err(Error::InvalidData(format!(
+            self.check_cpl(addr, &cpld, true)?;
+            deadline = Instant::now() + NetTlp::LIBTLP_CPL_TIMEOUT;
+            let size = cpld.data_len();
+
+            if size > buf_len {
+                warn!(size, buf_len, "BUG: buf is too small");
+                return Err(self
+                    .stats
+                    .error(Error::InvalidData("Internal error".to_string())));
+            }
+            if size > n.saturating_sub(hdr_len) {
+                return Err(self.stats.error(Error::InvalidData(format!(
                     "TLP payload size is larger than the actual packet size: {} > {}",
                     size,
-                    (n - (nh_size + cpl_size))
-                )));
+                    n.saturating_sub(hdr_len)
+                ))));
             }
-            if size > buf_len {
-                dbg!("BUG: buf is too small", size, buf_len, cpld);
-                return Err(Error::InvalidData("Internal error".to_string()));
-            }
-
-            let tmp = &recv_buf[start..end];
-            buf[buf_start..buf_end].copy_from_slice(&recv_buf[start..end]);
-            received += tmp.len();
+            received += size;
+            Counters::add(&self.stats.bytes_in, size);
 
             if cpld.is_last_tlp() {
                 break;
@@ -269,11 +623,21 @@ impl NetTlp {
     }
 
     /// DMA write
+    ///
+    /// Nothing is sent for an empty `buf`.
     pub fn dma_write(&self, addr: u64, buf: &[u8]) -> Result<(), Error> {
         assert!(
-            addr & 0x3 == 0 && buf.len() % 4 == 0,
+            addr & 0x3 == 0 && buf.len().is_multiple_of(4),
             "non DW-aligned requests are not implemented"
         );
+        if buf.is_empty() {
+            return Ok(());
+        }
+        dma_span!(
+            "dma_write",
+            addr = format_args!("{:#x}", addr),
+            len = buf.len()
+        );
         let total_len = buf.len();
         let mut p = addr;
         let mut sent = 0;
@@ -284,6 +648,7 @@ impl NetTlp {
             let len = min(min(remain, self.mrrs), max_len);
             let end = sent + len;
 
+            self.stats.dma_tlp(false, sent == 0);
             self.send_mwr(p, len, &buf[sent..end])?;
 
             sent += len;
@@ -300,6 +665,215 @@ impl NetTlp {
         self.dma_write(addr, t.as_bytes())?;
         Ok(())
     }
+
+    /// Read a byte from an I/O address `addr`
+    pub fn io_read_u8(&self, addr: u32) -> Result<u8, Error> {
+        let mut buf = [0u8; 1];
+        self.io_read(addr, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Read a word from an I/O address `addr`
+    ///
+    /// Returns `Error::InvalidAddress` if the access crosses a DW boundary.
+    pub fn io_read_u16(&self, addr: u32) -> Result<u16, Error> {
+        let mut buf = [0u8; 2];
+        self.io_read(addr, &mut buf)?;
+        Ok(u16::from_ne_bytes(buf))
+    }
+
+    /// Read a DW from an I/O address `addr`
+    ///
+    /// Returns `Error::InvalidAddress` if the access crosses a DW boundary.
+    pub fn io_read_u32(&self, addr: u32) -> Result<u32, Error> {
+        let mut buf = [0u8; 4];
+        self.io_read(addr, &mut buf)?;
+        Ok(u32::from_ne_bytes(buf))
+    }
+
+    /// Write a byte to an I/O address `addr`
+    pub fn io_write_u8(&self, addr: u32, v: u8) -> Result<(), Error> {
+        self.io_write(addr, v.as_bytes())
+    }
+
+    /// Write a word to an I/O address `addr`
+    ///
+    /// Returns `Error::InvalidAddress` if the access crosses a DW boundary.
+    pub fn io_write_u16(&self, addr: u32, v: u16) -> Result<(), Error> {
+        self.io_write(addr, v.as_bytes())
+    }
+
+    /// Write a DW to an I/O address `addr`
+    ///
+    /// Returns `Error::InvalidAddress` if the access crosses a DW boundary.
+    pub fn io_write_u32(&self, addr: u32, v: u32) -> Result<(), Error> {
+        self.io_write(addr, v.as_bytes())
+    }
+
+    // I/O requests are always 1 DW long, so the access must not cross a DW boundary
+    fn io_read(&self, addr: u32, buf: &mut [u8]) -> Result<(), Error> {
+        let offset = (addr & 0x3) as usize;
+        if offset + buf.len() > 4 {
+            return Err(Error::InvalidAddress(addr as u64));
+        }
+        self.send_mr(addr as u64, buf.len(), tlp::TlpType::IoRd, None)?;
+
+        let nh_size = std::mem::size_of::<NetTlpHdr>();
+        let cpl_size = std::mem::size_of::<tlp::TlpCplHdr>();
+        let mut recv_buf = [0u8; 64];
+        let (n, cpl) = self.recv_cpl_hdr(&mut recv_buf, is_io_reply)?;
+        self.check_cpl(addr as u64, &cpl, true)?;
+        // The completion always carries the whole DW
+        if n < nh_size + cpl_size + 4 {
+            return Err(self.stats.error(Error::InvalidData(format!(
+                "TLP payload size is smaller than 1 DW: {}",
+                n - (nh_size + cpl_size)
+            ))));
+        }
+        let start = nh_size + cpl_size + offset;
+        buf.copy_from_slice(&recv_buf[start..start + buf.len()]);
+        Counters::add(&self.stats.bytes_in, buf.len());
+        Ok(())
+    }
+
+    fn io_write(&self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        let offset = (addr & 0x3) as usize;
+        if offset + data.len() > 4 {
+            return Err(Error::InvalidAddress(addr as u64));
+        }
+        let mut dw = [0u8; 4];
+        dw[offset..offset + data.len()].copy_from_slice(data);
+        self.send_mr(addr as u64, data.len(), tlp::TlpType::IoWr, Some(&dw))?;
+
+        let mut recv_buf = [0u8; 64];
+        let (_, cpl) = self.recv_cpl_hdr(&mut recv_buf, is_io_reply)?;
+        self.check_cpl(addr as u64, &cpl, false)
+    }
+
+    // Check the format type and status of a completion for a request to `addr`
+    pub(crate) fn check_cpl(
+        &self,
+        addr: u64,
+        cpl: &tlp::TlpCplHdr,
+        with_data: bool,
+    ) -> Result<(), Error> {
+        if !cpl.is_completion() && !cpl.is_completion_with_data() {
+            return Err(self.stats.error(Error::InvalidData(format!(
+                "Invalid format type: {:#010b}",
+                cpl.fmt_type
+            ))));
+        }
+        if !cpl.is_valid_status() {
+            Counters::add(&self.stats.unsuccessful, 1);
+            if cpl.status() == tlp::CplStatus::Unsupported {
+                Counters::add(&self.stats.unsupported, 1);
+            }
+            return Err(Error::CompletionStatus {
+                status: cpl.status(),
+                completer: cpl.completer(),
+                tag: cpl.tag,
+                addr,
+            });
+        }
+        if with_data != cpl.is_completion_with_data() {
+            return Err(self.stats.error(Error::InvalidData(format!(
+                "Invalid format type: {:#010b}",
+                cpl.fmt_type
+            ))));
+        }
+        Ok(())
+    }
+
+    /// Atomically add `v` to the value at `addr` and return the original value
+    ///
+    /// Returns `Error::InvalidAddress` if `addr` is not naturally aligned.
+    pub fn atomic_fetch_add<T: AtomicOperand>(&self, addr: u64, v: T) -> Result<T, Error> {
+        self.atomic_op(addr, tlp::TlpType::FetchAdd, v.as_bytes())
+    }
+
+    /// Atomically replace the value at `addr` with `v` and return the original value
+    ///
+    /// Returns `Error::InvalidAddress` if `addr` is not naturally aligned.
+    pub fn atomic_swap<T: AtomicOperand>(&self, addr: u64, v: T) -> Result<T, Error> {
+        self.atomic_op(addr, tlp::TlpType::Swap, v.as_bytes())
+    }
+
+    /// Atomically replace the value at `addr` with `new` if it equals to `current`
+    ///
+    /// The original value is returned regardless of whether the swap happened.
+    /// Returns `Error::InvalidAddress` if `addr` is not naturally aligned.
+    pub fn atomic_cas<T: CasOperand>(&self, addr: u64, current: T, new: T) -> Result<T, Error> {
+        // Compare value comes first, followed by swap value
+        let mut data = Vec::with_capacity(std::mem::size_of::<T>() * 2);
+        data.extend_from_slice(current.as_bytes());
+        data.extend_from_slice(new.as_bytes());
+        self.atomic_op(addr, tlp::TlpType::Cas, &data)
+    }
+
+    // Send an AtomicOp request and receive the original value from its completion
+    fn atomic_op<T: FromBytes + AsBytes>(
+        &self,
+        addr: u64,
+        t: tlp::TlpType,
+        data: &[u8],
+    ) -> Result<T, Error> {
+        let size = std::mem::size_of::<T>();
+        // The address must be naturally aligned
+        if !addr.is_multiple_of(size as u64) {
+            return Err(Error::InvalidAddress(addr));
+        }
+        let mut v = T::new_zeroed();
+        self.send_mr(addr, data.len(), t, Some(data))?;
+        let mut buf = v.as_bytes_mut();
+        self.recv_cpld(addr, &mut buf.chunk_mut()[..size], false)?;
+        Ok(v)
+    }
+}
+
+// Whether a completion is that of an I/O request, whose lower address is 0
+// and byte count is 4
+fn is_io_reply(lowaddr: u8, count: u16) -> bool {
+    lowaddr == 0 && count == 4
+}
+
+// Find the in-flight read request that `cpld` completes by the tag
+pub(crate) fn find_inflight(inflight: &[InflightMrd], cpld: &tlp::TlpCplHdr) -> Option<usize> {
+    // Like `NetTlp::recv_cpld()`, an unsuccessful completion must also match the request
+    inflight
+        .iter()
+        .position(|m| m.tag == cpld.tag && m.is_next(cpld.lowaddr & 0x7F, cpld.count()))
+}
+
+// Return the valid data of a completion with data datagram
+pub(crate) fn cpld_data<'a>(packet: &'a [u8], cpld: &tlp::TlpCplHdr) -> Result<&'a [u8], Error> {
+    let nh_size = std::mem::size_of::<NetTlpHdr>();
+    let cpl_size = std::mem::size_of::<tlp::TlpCplHdr>();
+    let start = nh_size + cpl_size + (cpld.lowaddr & 0x3) as usize;
+    let size = cpld.data_len();
+    if start + size > packet.len() {
+        return Err(Error::InvalidData(format!(
+            "TLP payload size is larger than the actual packet size: {} > {}",
+            size,
+            packet.len().saturating_sub(start)
+        )));
+    }
+    Ok(&packet[start..start + size])
+}
+
+// Parse the completion header of a datagram
+fn parse_cpl_hdr(packet: &[u8]) -> Result<tlp::TlpCplHdr, Error> {
+    let nh_size = std::mem::size_of::<NetTlpHdr>();
+    let cpl_size = std::mem::size_of::<tlp::TlpCplHdr>();
+    if packet.len() < nh_size + cpl_size {
+        return Err(Error::InvalidData(format!(
+            "Datagram size is less than TLP header size: {} < {}",
+            packet.len(),
+            nh_size + cpl_size
+        )));
+    }
+
+    let cpl: tlp::TlpCplHdr = unsafe { std::ptr::read(packet.as_ptr().add(nh_size) as *const _) };
+    Ok(cpl)
 }
 
 // for debug
@@ -327,7 +901,10 @@ fn dump_packet(p: &[u8], nettlp: bool) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutil::{cpl, cpld, respond, Mrd, UdpAdapter};
+    use crate::transport::ChannelTransport;
     use std::str::FromStr;
+    use std::time::Duration;
 
     #[test]
     fn init() {
@@ -337,6 +914,494 @@ mod tests {
         let dir = DmaDirection::DmaIssuedByLibTLP;
         let tag = 0;
         let mrrs = 512;
-        let _ = NetTlp::new(bdf, local_addr, remote_addr, tag, mrrs, dir).unwrap();
+        let nettlp = NetTlp::new(bdf, local_addr, remote_addr, tag, mrrs, dir).unwrap();
+        assert_eq!(nettlp.remote_addr(), Some(remote_addr));
+        assert_eq!(nettlp.local_addr(), Some(local_addr));
+        assert!(matches!(
+            nettlp.dir(),
+            Some(DmaDirection::DmaIssuedByLibTLP)
+        ));
+        assert_eq!(
+            nettlp.socket().unwrap().local_addr().unwrap().port(),
+            UdpTransport::port(tag, dir)
+        );
+
+        let (transport, _) = ChannelTransport::pair();
+        let nettlp = NetTlp::with_transport(bdf, tag, mrrs, transport);
+        assert!(nettlp.remote_addr().is_none());
+        assert!(nettlp.socket().is_none());
+    }
+
+    #[test]
+    fn atomic_fetch_add() {
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let tag = 1;
+        let (transport, adapter) = ChannelTransport::pair();
+
+        // Pretend to be the adapter: reply the original value 0x11223344
+        let th = respond(adapter, move |tlp| {
+            // 3DW header (12) + operand (4)
+            assert_eq!(tlp.len(), 16);
+            assert_eq!(tlp[0], 0x4c);
+            assert_eq!(&tlp[12..16], 5u32.as_bytes());
+            vec![cpld(tag, 0, 4, 0x11223344u32.as_bytes())]
+        });
+
+        let nettlp = NetTlp::with_transport(bdf, tag, 512, transport);
+        let v = nettlp.atomic_fetch_add(0x1000, 5u32).unwrap();
+        assert_eq!(v, 0x11223344);
+
+        // Nothing is sent for a misaligned address
+        assert!(matches!(
+            nettlp.atomic_fetch_add(0x1002, 5u32),
+            Err(Error::InvalidAddress(0x1002))
+        ));
+        assert!(matches!(
+            nettlp.atomic_swap(0x1004, 5u64),
+            Err(Error::InvalidAddress(0x1004))
+        ));
+        assert!(matches!(
+            nettlp.atomic_cas(0x1001, 0u32, 1u32),
+            Err(Error::InvalidAddress(0x1001))
+        ));
+        drop(nettlp);
+        assert_eq!(th.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn io() {
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let tag = 4;
+        let (transport, adapter) = ChannelTransport::pair();
+
+        // Pretend to be the adapter: complete a write, reply 0x11223344 to a read
+        // and UR to the rest
+        let mut n = 0;
+        let th = respond(adapter, move |tlp| {
+            n += 1;
+            match n {
+                1 => {
+                    // IoWr of 2 bytes at 0xcfe
+                    assert_eq!(
+                        tlp,
+                        [0x42, 0, 0, 1, 1, 0, tag, 0x0c, 0, 0, 0x0c, 0xfc, 0, 0, 0xcd, 0xab]
+                    );
+                    vec![cpl(tag, 0, 4)]
+                }
+                2 => {
+                    // IoRd of a byte at 0x3f9
+                    assert_eq!(tlp, [0x02, 0, 0, 1, 1, 0, tag, 0x02, 0, 0, 0x03, 0xf8]);
+                    vec![cpld(tag, 0, 4, &0x11223344u32.to_le_bytes())]
+                }
+                _ => vec![cpl(tag, 1, 4)],
+            }
+        });
+
+        let nettlp = NetTlp::with_transport(bdf, tag, 512, transport);
+        nettlp.io_write_u16(0xcfe, 0xabcd).unwrap();
+        assert_eq!(nettlp.io_read_u8(0x3f9).unwrap(), 0x33);
+        for r in [
+            nettlp.io_read_u32(0x3f8).map(|_| ()),
+            nettlp.io_write_u32(0x3f8, 0),
+        ] {
+            assert!(matches!(
+                r,
+                Err(Error::CompletionStatus {
+                    status: tlp::CplStatus::Unsupported,
+                    addr: 0x3f8,
+                    ..
+                })
+            ));
+        }
+        assert!(matches!(
+            nettlp.io_read_u32(0x3f9),
+            Err(Error::InvalidAddress(0x3f9))
+        ));
+        assert!(matches!(
+            nettlp.io_write_u16(0xcff, 0),
+            Err(Error::InvalidAddress(0xcff))
+        ));
+        drop(nettlp);
+        assert_eq!(th.join().unwrap(), 4);
+    }
+
+    #[test]
+    fn io_after_late_completion() {
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let tag = 5;
+        let (transport, adapter) = ChannelTransport::pair();
+
+        // Pretend to be the adapter: reply a late completion of a 4-byte memory read
+        // of 0x1010 before the completion of the I/O read
+        let th = respond(adapter, move |_| {
+            vec![
+                cpld(tag, 0x10, 4, &0xeeeeeeeeu32.to_le_bytes()),
+                cpld(tag, 0, 4, &0x11223344u32.to_le_bytes()),
+            ]
+        });
+
+        let nettlp = NetTlp::with_transport(bdf, tag, 512, transport);
+        assert_eq!(nettlp.io_read_u32(0x3f8).unwrap(), 0x11223344);
+        assert_eq!(nettlp.stats().stale, 1);
+        drop(nettlp);
+        assert_eq!(th.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn completion_status() {
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let tag = 2;
+        let (transport, adapter) = ChannelTransport::pair();
+
+        // Pretend to be the adapter: reply Cpl with UR status from 00:00.0
+        let th = respond(adapter, move |_| vec![cpl(tag, 1, 4)]);
+
+        let nettlp = NetTlp::with_transport(bdf, tag, 512, transport);
+        let mut v = 0u32;
+        match nettlp.dma_read_t(0x1000, &mut v) {
+            Err(Error::CompletionStatus {
+                status: tlp::CplStatus::Unsupported,
+                completer,
+                tag: t,
+                addr: 0x1000,
+            }) => {
+                assert_eq!(completer, pci::Bdf::new(0, 0, 0));
+                assert_eq!(t, tag);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        drop(nettlp);
+        th.join().unwrap();
+    }
+
+    #[test]
+    fn stale_unsuccessful_completion() {
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let tag = 9;
+        let (transport, adapter) = ChannelTransport::pair();
+
+        // Pretend to be the adapter: reply a late UR completion of another request
+        // before the completion of the read
+        let th = respond(adapter, move |tlp| {
+            let mrd = Mrd::parse(tlp).unwrap();
+            vec![cpl(tag, 1, 4), mrd.reply(|_| 0xaa)]
+        });
+
+        let nettlp = NetTlp::with_transport(bdf, tag, 512, transport);
+        let mut buf = vec![];
+        nettlp.dma_read(0x1010, &mut buf, 8).unwrap();
+        assert_eq!(buf, [0xaa; 8]);
+        let stats = nettlp.stats();
+        assert_eq!(stats.stale, 1);
+        assert_eq!(stats.unsuccessful, 0);
+        drop(nettlp);
+        assert_eq!(th.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn dma_read_batch() {
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let tags = 3..6;
+        let (transport, adapter) = ChannelTransport::pair();
+
+        // Pretend to be the adapter: reply completions in the reverse order
+        // with the index of the request as data.
+        // The first and the last requests have the same lower address and byte count,
+        // so only the tag tells their completions apart.
+        let mut mrds = vec![];
+        let th = respond(adapter, move |tlp| {
+            mrds.push(Mrd::parse(tlp).unwrap());
+            if mrds.len() < 3 {
+                return vec![];
+            }
+            let mut tags: Vec<_> = mrds.iter().map(|m| m.tag).collect();
+            tags.sort();
+            assert_eq!(tags, [3, 4, 5]);
+            let index = |m: &Mrd| match m.addr {
+                0x1000 => 1,
+                0x1044 => 2,
+                _ => 3,
+            };
+            mrds.iter().rev().map(|m| m.reply(|_| index(m))).collect()
+        });
+
+        let nettlp = NetTlp::with_transport_tags(bdf, tags, 512, transport);
+        let mut a = [0u8; 8];
+        let mut b = [0u8; 4];
+        let mut c = [0u8; 8];
+        let mut reqs = [
+            DmaReadRequest {
+                addr: 0x1000,
+                buf: &mut a,
+            },
+            DmaReadRequest {
+                addr: 0x1044,
+                buf: &mut b,
+            },
+            DmaReadRequest {
+                addr: 0x2000,
+                buf: &mut c,
+            },
+        ];
+        nettlp.dma_read_batch(&mut reqs).unwrap();
+        assert_eq!(a, [1; 8]);
+        assert_eq!(b, [2; 4]);
+        assert_eq!(c, [3; 8]);
+        drop(nettlp);
+        assert_eq!(th.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn dma_read_batch_single_tag() {
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let tag = 8;
+        let (transport, adapter) = ChannelTransport::pair();
+
+        // Pretend to be the adapter: reply a completion that looks like the one for 0x2000
+        // before the completion of the first request, then the index of the request as data
+        let th = respond(adapter, move |tlp| {
+            let mrd = Mrd::parse(tlp).unwrap();
+            match mrd.addr {
+                0x1010 => vec![cpld(tag, 0, 8, &[0xee; 8]), mrd.reply(|_| 1)],
+                _ => vec![mrd.reply(|_| 2)],
+            }
+        });
+
+        // The requests are sent one by one, so the stale completion is dropped rather than
+        // taken for the completion of 0x2000
+        let nettlp = NetTlp::with_transport(bdf, tag, 512, transport);
+        let mut a = [0u8; 8];
+        let mut b = [0u8; 8];
+        let mut reqs = [
+            DmaReadRequest {
+                addr: 0x1010,
+                buf: &mut a,
+            },
+            DmaReadRequest {
+                addr: 0x2000,
+                buf: &mut b,
+            },
+        ];
+        nettlp.dma_read_batch(&mut reqs).unwrap();
+        assert_eq!(a, [1; 8]);
+        assert_eq!(b, [2; 8]);
+        assert_eq!(nettlp.stats().stale, 1);
+        drop(nettlp);
+        assert_eq!(th.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn with_tags() {
+        let local_addr = Ipv4Addr::new(127, 0, 0, 1);
+        let remote_addr = Ipv4Addr::new(127, 0, 0, 2);
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let dir = DmaDirection::DmaIssuedByLibTLP;
+        let tags = 16..18;
+
+        // Pretend to be the adapter on the port of each tag: reply the tag as data
+        let adapters: Vec<_> = tags
+            .clone()
+            .map(|tag| {
+                let adapter = UdpAdapter::bind(remote_addr, UdpTransport::port(tag, dir));
+                respond(adapter, move |tlp| {
+                    let mrd = Mrd::parse(tlp).unwrap();
+                    assert_eq!(mrd.tag, tag);
+                    vec![mrd.reply(|_| tag)]
+                })
+            })
+            .collect();
+
+        let nettlp = NetTlp::with_tags(bdf, local_addr, remote_addr, tags, 512, dir).unwrap();
+        assert_eq!(nettlp.tags().collect::<Vec<_>>(), [16, 17]);
+        let mut bufs = [[0u8; 16]; 4];
+        let mut reqs: Vec<_> = bufs
+            .iter_mut()
+            .map(|buf| DmaReadRequest { addr: 0x1000, buf })
+            .collect();
+        nettlp.dma_read_batch(&mut reqs).unwrap();
+        assert_eq!(bufs[..2], [[16; 16], [17; 16]]);
+        // A tag is reused as soon as its request completes
+        assert!(bufs[2..].iter().all(|b| *b == [16; 16] || *b == [17; 16]));
+        let requests: usize = adapters.into_iter().map(|th| th.join().unwrap()).sum();
+        assert_eq!(requests, 4);
+
+        // Ports of DmaIssuedByAdapter mode wrap around every 16 tags
+        let dir = DmaDirection::DmaIssuedByAdapter;
+        assert!(matches!(
+            UdpTransport::with_tags(local_addr, remote_addr, 0..17, dir),
+            Err(Error::InvalidTags(r)) if r == (0..17)
+        ));
+        assert!(matches!(
+            NetTlp::with_tags(bdf, local_addr, remote_addr, 4..4, 512, dir),
+            Err(Error::InvalidTags(_))
+        ));
+    }
+
+    #[test]
+    fn send_message() {
+        use message::{Message, MsgRouting};
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let (transport, adapter) = ChannelTransport::pair();
+        let nettlp = NetTlp::with_transport(bdf, 0, 512, transport);
+
+        let vendor = |routing, payload| Message::VendorDefined {
+            type1: true,
+            routing,
+            target: pci::Bdf::new(2, 0, 1),
+            vendor_id: 0x1234,
+            vendor_data: 0xdeadbeef,
+            payload,
+        };
+        nettlp
+            .send_message(&vendor(MsgRouting::Id, vec![1, 2, 3, 4]))
+            .unwrap();
+        let mut buf = [0u8; 64];
+        let n = adapter.recv(&mut buf, NetTlp::LIBTLP_CPL_TIMEOUT).unwrap();
+        assert_eq!(
+            buf[6..n],
+            [0x72, 0, 0, 1, 1, 0, 0, 0x7f, 2, 1, 0x12, 0x34, 0xde, 0xad, 0xbe, 0xef, 1, 2, 3, 4]
+        );
+
+        for routing in [MsgRouting::Address, MsgRouting::Gather] {
+            assert!(matches!(
+                nettlp.send_message(&vendor(routing, vec![])),
+                Err(Error::InvalidRouting(r)) if r == routing
+            ));
+        }
+        assert!(matches!(
+            nettlp.send_message(&vendor(MsgRouting::Local, vec![0; 0x1004])),
+            Err(Error::InvalidLength(0x1004))
+        ));
+        assert!(matches!(
+            nettlp.send_message(&vendor(MsgRouting::Local, vec![0; 3])),
+            Err(Error::InvalidLength(3))
+        ));
+        assert!(adapter.recv(&mut buf, Duration::ZERO).is_err());
+        assert_eq!(nettlp.stats().msg, 1);
+    }
+
+    #[test]
+    fn unaligned_read() {
+        let local_addr = Ipv4Addr::new(127, 0, 0, 1);
+        let remote_addr = Ipv4Addr::new(127, 0, 0, 2);
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let dir = DmaDirection::DmaIssuedByLibTLP;
+        let tag = 5;
+
+        // Pretend to be the adapter: reply 7 bytes from 0x103e in two completions
+        // split at the 64-byte boundary
+        let adapter = UdpAdapter::bind(remote_addr, UdpTransport::port(tag, dir));
+        let th = respond(adapter, move |tlp| {
+            assert_eq!(
+                Mrd::parse(tlp),
+                Some(Mrd {
+                    tag,
+                    addr: 0x103e,
+                    count: 7
+                })
+            );
+            vec![
+                cpld(tag, 0x3e, 7, &[0xff, 0xff, 1, 2]),
+                cpld(tag, 0x40, 5, &[3, 4, 5, 6, 7, 0xff, 0xff, 0xff]),
+            ]
+        });
+
+        let nettlp = NetTlp::new(bdf, local_addr, remote_addr, tag, 512, dir).unwrap();
+        let mut buf = vec![];
+        nettlp.dma_read(0x103e, &mut buf, 7).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(th.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn flush() {
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let tag = 7;
+        let (transport, adapter) = ChannelTransport::pair();
+
+        // Pretend to be the adapter: reply 1 DW with byte count 1 to zero-length reads
+        let th = respond(adapter, move |tlp| {
+            // 1 DW, no byte enabled
+            assert_eq!(tlp, [0, 0, 0, 1, 1, 0, tag, 0, 0, 0, 0x10, 0x40]);
+            vec![Mrd::parse(tlp).unwrap().reply(|_| 0xff)]
+        });
+
+        let nettlp = NetTlp::with_transport(bdf, tag, 512, transport);
+        nettlp.flush(0x1040).unwrap();
+        let mut buf = vec![];
+        nettlp.dma_read(0x1040, &mut buf, 0).unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(nettlp.stats().dma_reads, 2);
+        assert_eq!(nettlp.stats().bytes_in, 0);
+        drop(nettlp);
+        assert_eq!(th.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn flush_after_late_completion() {
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let tag = 7;
+        let (transport, adapter) = ChannelTransport::pair();
+
+        // Pretend to be the adapter: reply a late completion of an 8-byte read of 0x1040
+        // before the completion of the zero-length read
+        let th = respond(adapter, move |tlp| {
+            let mrd = Mrd::parse(tlp).unwrap();
+            vec![cpld(tag, 0x40, 8, &[0xee; 8]), mrd.reply(|_| 0xff)]
+        });
+
+        // The flush waits for its own completion
+        let nettlp = NetTlp::with_transport(bdf, tag, 512, transport);
+        nettlp.flush(0x1040).unwrap();
+        assert_eq!(nettlp.stats().stale, 1);
+        drop(nettlp);
+        assert_eq!(th.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn stats() {
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let tag = 6;
+        let (transport, adapter) = ChannelTransport::pair();
+
+        // Pretend to be the adapter: complete two 64-byte reads, ignore a write,
+        // then reply a truncated datagram and a UR completion
+        let mut n = 0;
+        let th = respond(adapter, move |tlp| {
+            n += 1;
+            match n {
+                1 | 2 => vec![Mrd::parse(tlp).unwrap().reply(|_| 0xaa)],
+                4 => vec![vec![0u8; 9]],
+                5 => vec![cpl(tag, 1, 4)],
+                _ => vec![],
+            }
+        });
+
+        let nettlp = NetTlp::with_transport(bdf, tag, 64, transport);
+        let mut buf = vec![];
+        nettlp.dma_read(0x1000, &mut buf, 128).unwrap();
+        nettlp.dma_write(0x2000, &[1; 8]).unwrap();
+        let mut v = 0u32;
+        assert!(nettlp.dma_read_t(0x3000, &mut v).is_err());
+        assert!(nettlp.dma_read_t(0x4000, &mut v).is_err());
+
+        let stats = nettlp.stats();
+        assert_eq!(stats.mrd, 4);
+        assert_eq!(stats.mwr, 1);
+        assert_eq!(stats.dma_reads, 3);
+        assert_eq!(stats.dma_writes, 1);
+        assert_eq!(stats.splits, 1);
+        assert_eq!(stats.completions, 3);
+        assert_eq!(stats.unsuccessful, 1);
+        assert_eq!(stats.unsupported, 1);
+        assert_eq!(stats.bytes_out, 8);
+        assert_eq!(stats.bytes_in, 128);
+        assert_eq!(stats.malformed, 1);
+        assert_eq!(stats.timeouts, 0);
+
+        nettlp.reset_stats();
+        assert_eq!(nettlp.stats(), NetTlpStats::default());
+        drop(nettlp);
+        assert_eq!(th.join().unwrap(), 5);
     }
 }
@@ -0,0 +1,431 @@
+// AF_PACKET transport that bypasses the IP stack
+//
+// References
+//   - https://www.kernel.org/doc/html/latest/networking/packet_mmap.html
+
+use crate::error::Error;
+use crate::ether::{self, UdpFlow, ETH_P_IP};
+use crate::nettlp::DmaDirection;
+use crate::transport::{split_copy, Transport, UdpTransport};
+
+use bytes::buf::UninitSlice;
+
+use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{fence, AtomicU16, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// PACKET_MMAP definitions (linux/if_packet.h)
+const PACKET_RX_RING: libc::c_int = 5;
+const PACKET_VERSION: libc::c_int = 10;
+const PACKET_IGNORE_OUTGOING: libc::c_int = 23;
+const TPACKET_V3: libc::c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: u32,
+    tp_block_nr: u32,
+    tp_frame_size: u32,
+    tp_frame_nr: u32,
+    tp_retire_blk_tov: u32,
+    tp_sizeof_priv: u32,
+    tp_feature_req_word: u32,
+}
+
+// struct tpacket_block_desc with struct tpacket_hdr_v1
+#[repr(C)]
+struct TpacketBlockDesc {
+    version: u32,
+    offset_to_priv: u32,
+    block_status: u32,
+    num_pkts: u32,
+    offset_to_first_pkt: u32,
+    blk_len: u32,
+}
+
+// struct tpacket3_hdr (only the fields used)
+#[repr(C)]
+struct Tpacket3Hdr {
+    tp_next_offset: u32,
+    tp_sec: u32,
+    tp_nsec: u32,
+    tp_snaplen: u32,
+    tp_len: u32,
+    tp_status: u32,
+    tp_mac: u16,
+    tp_net: u16,
+}
+
+/// AF_PACKET (SOCK_RAW) transport to the NetTLP adapter
+///
+/// Ethernet/IPv4/UDP headers are crafted by this transport, so no IP address needs to be
+/// configured on the interface. As there is no ARP, the MAC address of the adapter is required.
+///
+/// Optionally, packets can be received through a PACKET_MMAP (TPACKET_V3) ring
+/// (see `PacketTransport::with_rx_ring()`).
+/// Without a ring, datagrams are received into the caller's buffer directly,
+/// so datagrams with IPv4 options, which the adapter does not send, are ignored.
+#[derive(Debug)]
+pub struct PacketTransport {
+    pub ifname: String,
+    pub local_mac: [u8; 6],
+    pub remote_mac: [u8; 6],
+    pub local_addr: Ipv4Addr,
+    pub remote_addr: Ipv4Addr,
+    pub port: u16,
+    fd: OwnedFd,
+    ip_id: AtomicU16,
+    ring: Option<Mutex<RxRing>>,
+}
+
+#[derive(Debug)]
+struct RxRing {
+    map: *mut u8,
+    block_size: usize,
+    block_nr: usize,
+    /// Current block
+    block: usize,
+    /// Number of packets consumed in the current block
+    pkt: u32,
+    /// Offset of the next packet in the current block
+    offset: usize,
+}
+
+// The ring is only accessed under the Mutex
+unsafe impl Send for RxRing {}
+
+impl Drop for RxRing {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut _, self.block_size * self.block_nr);
+        }
+    }
+}
+
+impl PacketTransport {
+    /// The timeout of retiring a ring block (ms)
+    const RING_RETIRE_BLK_TOV: u32 = 1;
+    /// The frame size of a ring
+    const RING_FRAME_SIZE: usize = 2048;
+
+    /// Open an AF_PACKET socket on `ifname` for `tag`
+    pub fn new(
+        ifname: &str,
+        remote_mac: [u8; 6],
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        tag: u8,
+        dir: DmaDirection,
+    ) -> Result<Self, Error> {
+        let local_mac = ether::read_mac(ifname)?;
+        let ifindex = ether::ifindex(ifname)?;
+
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_RAW,
+                (ETH_P_IP.to_be()) as libc::c_int,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut sll: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        sll.sll_family = libc::AF_PACKET as u16;
+        sll.sll_protocol = ETH_P_IP.to_be();
+        sll.sll_ifindex = ifindex as i32;
+        let r = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &sll as *const _ as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if r < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        // Packets sent by ourselves are not necessary (best effort, Linux 4.20+)
+        let _ = setsockopt(fd.as_raw_fd(), PACKET_IGNORE_OUTGOING, &1 as &libc::c_int);
+
+        Ok(PacketTransport {
+            ifname: ifname.to_string(),
+            local_mac,
+            remote_mac,
+            local_addr,
+            remote_addr,
+            port: UdpTransport::port(tag, dir),
+            fd,
+            ip_id: AtomicU16::new(0),
+            ring: None,
+        })
+    }
+
+    /// Receive packets through a TPACKET_V3 ring of `block_nr` blocks of `block_size` bytes
+    ///
+    /// `block_size` must be a multiple of the page size.
+    /// Note that a block is passed to the user when it is full or its timeout (1ms) expires.
+    pub fn with_rx_ring(mut self, block_size: usize, block_nr: usize) -> Result<Self, Error> {
+        let fd = self.fd.as_raw_fd();
+        setsockopt(fd, PACKET_VERSION, &TPACKET_V3)?;
+        let req = TpacketReq3 {
+            tp_block_size: block_size as u32,
+            tp_block_nr: block_nr as u32,
+            tp_frame_size: PacketTransport::RING_FRAME_SIZE as u32,
+            tp_frame_nr: (block_size * block_nr / PacketTransport::RING_FRAME_SIZE) as u32,
+            tp_retire_blk_tov: PacketTransport::RING_RETIRE_BLK_TOV,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        setsockopt(fd, PACKET_RX_RING, &req)?;
+
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                block_size * block_nr,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_LOCKED,
+                fd,
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        self.ring = Some(Mutex::new(RxRing {
+            map: map as *mut u8,
+            block_size,
+            block_nr,
+            block: 0,
+            pkt: 0,
+            offset: 0,
+        }));
+        Ok(self)
+    }
+
+    fn flow(&self) -> UdpFlow {
+        UdpFlow {
+            local_mac: self.local_mac,
+            remote_mac: self.remote_mac,
+            local_addr: self.local_addr,
+            remote_addr: self.remote_addr,
+            port: self.port,
+        }
+    }
+
+    // Receive a datagram of this flow into `hdr` and `payload` (truncated) without
+    // an intermediate buffer. The Ethernet/IPv4/UDP headers are received into the stack,
+    // so datagrams with IPv4 options are not taken, as is the case with `XdpTransport`.
+    fn recv_socket(
+        &self,
+        hdr: &mut [u8],
+        payload: &mut UninitSlice,
+        deadline: Instant,
+    ) -> Result<usize, Error> {
+        let mut headers = [0u8; ether::HDR_SIZE];
+        let mut iovs = [
+            libc::iovec {
+                iov_base: headers.as_mut_ptr() as *mut _,
+                iov_len: headers.len(),
+            },
+            libc::iovec {
+                iov_base: hdr.as_mut_ptr() as *mut _,
+                iov_len: hdr.len(),
+            },
+            libc::iovec {
+                iov_base: payload.as_mut_ptr() as *mut _,
+                iov_len: payload.len(),
+            },
+        ];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = iovs.as_mut_ptr();
+        msg.msg_iovlen = iovs.len() as _;
+        let flow = self.flow();
+        loop {
+            ether::poll(self.fd.as_raw_fd(), deadline)?;
+            // MSG_TRUNC returns the length of the frame even if it is truncated
+            let n = unsafe {
+                libc::recvmsg(
+                    self.fd.as_raw_fd(),
+                    &mut msg,
+                    libc::MSG_DONTWAIT | libc::MSG_TRUNC,
+                )
+            };
+            if n < 0 {
+                let e = std::io::Error::last_os_error();
+                match e.kind() {
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted => continue,
+                    _ => return Err(e.into()),
+                }
+            }
+            if let Some(range) = flow.payload(&headers, n as usize) {
+                return Ok(std::cmp::min(range.len(), hdr.len() + payload.len()));
+            }
+        }
+    }
+
+    // Receive a datagram of this flow from the ring and pass its payload to `f`
+    fn recv_ring(
+        &self,
+        ring: &Mutex<RxRing>,
+        deadline: Instant,
+        f: &mut dyn FnMut(&[u8]) -> usize,
+    ) -> Result<usize, Error> {
+        let flow = self.flow();
+        let mut ring = ring.lock().unwrap();
+        loop {
+            let block = unsafe { ring.map.add(ring.block * ring.block_size) };
+            let desc = block as *mut TpacketBlockDesc;
+            let status = unsafe { std::ptr::read_volatile(&(*desc).block_status) };
+            if status & TP_STATUS_USER == 0 {
+                ether::poll(self.fd.as_raw_fd(), deadline)?;
+                continue;
+            }
+            fence(Ordering::Acquire);
+
+            let num_pkts = unsafe { (*desc).num_pkts };
+            if ring.pkt == 0 {
+                ring.offset = unsafe { (*desc).offset_to_first_pkt } as usize;
+            }
+            let mut received = None;
+            while ring.pkt < num_pkts && received.is_none() {
+                let hdr = unsafe { &*(block.add(ring.offset) as *const Tpacket3Hdr) };
+                let frame = unsafe {
+                    std::slice::from_raw_parts(
+                        block.add(ring.offset + hdr.tp_mac as usize),
+                        hdr.tp_snaplen as usize,
+                    )
+                };
+                if let Some(payload) = flow.parse(frame) {
+                    received = Some(f(payload));
+                }
+                ring.offset += hdr.tp_next_offset as usize;
+                ring.pkt += 1;
+            }
+
+            if ring.pkt >= num_pkts {
+                // Return the block to the kernel
+                fence(Ordering::Release);
+                unsafe { std::ptr::write_volatile(&mut (*desc).block_status, TP_STATUS_KERNEL) };
+                ring.block = (ring.block + 1) % ring.block_nr;
+                ring.pkt = 0;
+            }
+            if let Some(n) = received {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+impl Transport for PacketTransport {
+    fn send(&self, packet: &[u8]) -> Result<(), Error> {
+        let mut frame = Vec::with_capacity(ether::HDR_SIZE + packet.len());
+        let id = self.ip_id.fetch_add(1, Ordering::Relaxed);
+        frame.extend_from_slice(&self.flow().headers(id, packet.len()));
+        frame.extend_from_slice(packet);
+        let n = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                frame.as_ptr() as *const _,
+                frame.len(),
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        self.recv_split(buf, UninitSlice::new(&mut []), timeout)
+    }
+
+    // The payload is copied from the ring frame, or received into `hdr` and `payload` directly
+    fn recv_split(
+        &self,
+        hdr: &mut [u8],
+        payload: &mut UninitSlice,
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let deadline = Instant::now() + timeout;
+        match &self.ring {
+            Some(ring) => self.recv_ring(ring, deadline, &mut |datagram| {
+                split_copy(datagram, hdr, payload)
+            }),
+            None => self.recv_socket(hdr, payload, deadline),
+        }
+    }
+
+    fn discard_pending(&self) {
+        let mut buf = [0u8; 64];
+        while self.recv(&mut buf, Duration::from_millis(0)).is_ok() {}
+    }
+}
+
+fn setsockopt<T>(fd: libc::c_int, opt: libc::c_int, val: &T) -> Result<(), Error> {
+    let r = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_PACKET,
+            opt,
+            val as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nettlp::NetTlp;
+    use crate::pci;
+    use crate::testutil::{cpld, respond, UdpAdapter, Veth};
+
+    fn dma_read_over_veth(name: &str, peer: &str, subnet: u8, ring: bool) {
+        let local_addr = Ipv4Addr::new(192, 168, subnet, 2);
+        let remote_addr = Ipv4Addr::new(192, 168, subnet, 1);
+        let _veth = Veth::new(name, peer, local_addr, remote_addr);
+        let bdf = pci::Bdf::new(1, 0, 0);
+        let dir = DmaDirection::DmaIssuedByLibTLP;
+        let tag = 0;
+
+        // Pretend to be the adapter: reply 0xdeadbeef
+        let adapter = UdpAdapter::bind(remote_addr, UdpTransport::port(tag, dir));
+        let th = respond(adapter, move |_| {
+            vec![cpld(tag, 0, 4, &0xdeadbeefu32.to_ne_bytes())]
+        });
+
+        let remote_mac = ether::read_mac(peer).unwrap();
+        let mut transport =
+            PacketTransport::new(name, remote_mac, local_addr, remote_addr, tag, dir).unwrap();
+        if ring {
+            transport = transport.with_rx_ring(1 << 16, 4).unwrap();
+        }
+        let nettlp = NetTlp::with_transport(bdf, tag, 512, transport);
+        let mut v = 0u32;
+        nettlp.dma_read_t(0x1000, &mut v).unwrap();
+        assert_eq!(v, 0xdeadbeef);
+        assert_eq!(th.join().unwrap(), 1);
+    }
+
+    #[test]
+    #[ignore = "requires root to create a veth pair"]
+    fn veth() {
+        dma_read_over_veth("tlpveth0", "tlpveth1", 250, false);
+    }
+
+    #[test]
+    #[ignore = "requires root to create a veth pair"]
+    fn veth_rx_ring() {
+        dma_read_over_veth("tlpveth2", "tlpveth3", 251, true);
+    }
+}
@@ -18,6 +18,20 @@ impl Bdf {
     pub(crate) fn to_u16(self) -> u16 {
         ((self.bus as u16) << 8) | ((self.device as u16) << 3) | (self.func as u16)
     }
+
+    pub(crate) fn from_u16(id: u16) -> Self {
+        Bdf {
+            bus: (id >> 8) as u8,
+            device: ((id >> 3) & 0x1F) as u8,
+            func: (id & 0x7) as u8,
+        }
+    }
+}
+
+impl std::fmt::Display for Bdf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02x}:{:02x}.{:x}", self.bus, self.device, self.func)
+    }
 }
 
 impl FromStr for Bdf {
@@ -56,4 +70,11 @@ mod tests {
         let b = Bdf::from_str("ff:05.1").unwrap();
         assert_eq!(a, b);
     }
+
+    #[test]
+    fn to_string() {
+        let a = Bdf::from_str("ff:05.1").unwrap();
+        assert_eq!(a.to_string(), "ff:05.1");
+        assert_eq!(Bdf::from_u16(a.to_u16()), a);
+    }
 }
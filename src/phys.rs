@@ -0,0 +1,404 @@
+//! Typed pointers to host physical memory
+//!
+//! `PhysPtr<T>` and `PhysSlice<T>` model device register blocks and in-memory structures
+//! instead of computing addresses and calling `NetTlp::dma_read_t()` by hand.
+//! Every access issues DMA, i.e., accesses are volatile.
+//!
+//! ```no_run
+//! use libtlp::phys::{Le32, Le64};
+//! use libtlp::{phys_field, PhysPtr};
+//! # use libtlp::{DmaDirection, NetTlp};
+//! # use libtlp::pci::Bdf;
+//! # use std::net::Ipv4Addr;
+//! use zerocopy::{AsBytes, FromBytes};
+//!
+//! #[repr(C)]
+//! #[derive(AsBytes, FromBytes)]
+//! struct Desc {
+//!     addr: Le64,
+//!     len: Le32,
+//!     flags: Le32,
+//! }
+//!
+//! # let nettlp = NetTlp::new(Bdf::new(1, 0, 0), Ipv4Addr::new(192, 168, 10, 3),
+//! #     Ipv4Addr::new(192, 168, 10, 1), 0, 512, DmaDirection::DmaIssuedByLibTLP).unwrap();
+//! let ring = PhysPtr::<Desc>::new(&nettlp, 0x10000);
+//! let flags = phys_field!(ring.offset(3), flags).read().unwrap().get();
+//! ```
+
+use crate::error::Error;
+use crate::nettlp::NetTlp;
+
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ops::Range;
+
+use zerocopy::byteorder::{BigEndian, LittleEndian, U16, U32, U64};
+use zerocopy::{AsBytes, FromBytes};
+
+/// Little-endian `u16`
+pub type Le16 = U16<LittleEndian>;
+/// Little-endian `u32`
+pub type Le32 = U32<LittleEndian>;
+/// Little-endian `u64`
+pub type Le64 = U64<LittleEndian>;
+/// Big-endian `u16`
+pub type Be16 = U16<BigEndian>;
+/// Big-endian `u32`
+pub type Be32 = U32<BigEndian>;
+/// Big-endian `u64`
+pub type Be64 = U64<BigEndian>;
+
+/// A pointer to a `T` at a host physical address
+///
+/// Note that writes have the same restriction as `NetTlp::dma_write()`:
+/// the address and the size of `T` must be DW-aligned.
+pub struct PhysPtr<'a, T> {
+    nettlp: &'a NetTlp,
+    addr: u64,
+    // A `PhysPtr` only holds an address, so it is `Send` and `Sync` regardless of `T`
+    _marker: PhantomData<fn() -> T>,
+}
+
+// Derive would require `T: Clone`
+impl<T> Clone for PhysPtr<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for PhysPtr<'_, T> {}
+
+impl<T> std::fmt::Debug for PhysPtr<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "PhysPtr<{}>({:#x})",
+            std::any::type_name::<T>(),
+            self.addr
+        )
+    }
+}
+
+impl<'a, T> PhysPtr<'a, T> {
+    /// Create a pointer to `addr` accessed through `nettlp`
+    pub fn new(nettlp: &'a NetTlp, addr: u64) -> Self {
+        PhysPtr {
+            nettlp,
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Physical address
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// `NetTlp` handle of this pointer
+    pub fn nettlp(&self) -> &'a NetTlp {
+        self.nettlp
+    }
+
+    /// Pointer to the `count`-th `T` from this pointer (like `pointer::offset()`)
+    pub fn offset(self, count: isize) -> Self {
+        let bytes = count.wrapping_mul(std::mem::size_of::<T>() as isize);
+        PhysPtr::new(self.nettlp, self.addr.wrapping_add_signed(bytes as i64))
+    }
+
+    /// Pointer to the same address with a different type
+    pub fn cast<U>(self) -> PhysPtr<'a, U> {
+        PhysPtr::new(self.nettlp, self.addr)
+    }
+
+    /// Pointer to a `U` at `offset` bytes from this pointer
+    pub fn byte_add<U>(self, offset: usize) -> PhysPtr<'a, U> {
+        PhysPtr::new(self.nettlp, self.addr.wrapping_add(offset as u64))
+    }
+}
+
+impl<'a, T: FromBytes> PhysPtr<'a, T> {
+    /// Pointer to a field of `T`
+    ///
+    /// `field` maps a pointer to `T` to a pointer to its field.
+    /// `phys_field!` is a shorthand of this.
+    /// `T` is `FromBytes` so that the pointer can be computed from an uninitialized `T`.
+    ///
+    /// # Panics
+    /// Panics if `field` returns a pointer outside of `T`.
+    pub fn project<U>(self, field: impl FnOnce(*const T) -> *const U) -> PhysPtr<'a, U> {
+        let base = MaybeUninit::<T>::uninit();
+        let p = base.as_ptr();
+        let offset = (field(p) as usize).wrapping_sub(p as usize);
+        assert!(
+            offset + std::mem::size_of::<U>() <= std::mem::size_of::<T>(),
+            "field is out of the struct"
+        );
+        PhysPtr::new(self.nettlp, self.addr + offset as u64)
+    }
+}
+
+impl<T: FromBytes + AsBytes> PhysPtr<'_, T> {
+    /// Read the value
+    pub fn read(&self) -> Result<T, Error> {
+        let mut t = T::new_zeroed();
+        self.nettlp.dma_read_t(self.addr, &mut t)?;
+        Ok(t)
+    }
+
+    /// Write `v`
+    pub fn write(&self, v: T) -> Result<(), Error> {
+        self.nettlp.dma_write_t(self.addr, v)
+    }
+}
+
+impl<'a, T: PhysStruct> PhysPtr<'a, T> {
+    /// Pointers to the fields of `T`
+    pub fn fields(self) -> T::Fields<'a> {
+        T::fields(self)
+    }
+}
+
+/// A struct whose fields can be accessed individually through a `PhysPtr`
+///
+/// Use `#[derive(PhysStruct)]` to implement this.
+/// The layout can be checked at compile time with `size` and `offset` attributes.
+///
+/// ```no_run
+/// use libtlp::phys::{Be32, PhysStruct};
+/// use libtlp::PhysPtr;
+/// # use libtlp::{DmaDirection, NetTlp};
+/// # use libtlp::pci::Bdf;
+/// # use std::net::Ipv4Addr;
+///
+/// #[repr(C)]
+/// #[derive(PhysStruct)]
+/// #[phys(size = 16)]
+/// struct Desc {
+///     addr: u64,
+///     #[phys(offset = 8)]
+///     len: Be32,
+///     flags: u32,
+/// }
+///
+/// # let nettlp = NetTlp::new(Bdf::new(1, 0, 0), Ipv4Addr::new(192, 168, 10, 3),
+/// #     Ipv4Addr::new(192, 168, 10, 1), 0, 512, DmaDirection::DmaIssuedByLibTLP).unwrap();
+/// let desc = PhysPtr::<Desc>::new(&nettlp, 0x10000);
+/// let len: u32 = desc.fields().len.read().unwrap().get();
+/// desc.fields().flags.write(0).unwrap();
+/// ```
+pub trait PhysStruct: Sized {
+    /// Pointers to the fields
+    type Fields<'a>;
+
+    /// Pointers to the fields of the struct at `ptr`
+    fn fields(ptr: PhysPtr<'_, Self>) -> Self::Fields<'_>;
+}
+
+pub use libtlp_derive::PhysStruct;
+
+/// Pointer to a field of the struct that a `PhysPtr` points to
+///
+/// `phys_field!(ptr, a.b)` is a `PhysPtr` to `a.b` of `*ptr`.
+/// This also works with packed structs. The struct must be `FromBytes`.
+#[macro_export]
+macro_rules! phys_field {
+    ($ptr:expr, $($field:ident).+) => {
+        $ptr.project(|p| unsafe { ::core::ptr::addr_of!((*p).$($field).+) })
+    };
+}
+
+/// An array of `T` at a host physical address
+pub struct PhysSlice<'a, T> {
+    ptr: PhysPtr<'a, T>,
+    len: usize,
+}
+
+impl<T> Clone for PhysSlice<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for PhysSlice<'_, T> {}
+
+impl<T> std::fmt::Debug for PhysSlice<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "PhysSlice<{}>({:#x}, {})",
+            std::any::type_name::<T>(),
+            self.ptr.addr,
+            self.len
+        )
+    }
+}
+
+impl<'a, T> PhysSlice<'a, T> {
+    /// Create a slice of `len` elements from `addr` accessed through `nettlp`
+    pub fn new(nettlp: &'a NetTlp, addr: u64, len: usize) -> Self {
+        PhysSlice {
+            ptr: PhysPtr::new(nettlp, addr),
+            len,
+        }
+    }
+
+    /// Pointer to the first element
+    pub fn as_ptr(&self) -> PhysPtr<'a, T> {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Pointer to the `i`-th element, or `None` if out of bounds
+    pub fn get(&self, i: usize) -> Option<PhysPtr<'a, T>> {
+        (i < self.len).then(|| self.ptr.offset(i as isize))
+    }
+
+    /// Pointer to the `i`-th element
+    ///
+    /// # Panics
+    /// Panics if `i` is out of bounds.
+    pub fn index(&self, i: usize) -> PhysPtr<'a, T> {
+        self.get(i).unwrap_or_else(|| {
+            panic!(
+                "index out of bounds: the len is {} but the index is {}",
+                self.len, i
+            )
+        })
+    }
+
+    /// Sub-slice of `range`
+    ///
+    /// # Panics
+    /// Panics if `range` is out of bounds.
+    pub fn slice(&self, range: Range<usize>) -> Self {
+        assert!(range.start <= range.end && range.end <= self.len);
+        PhysSlice {
+            ptr: self.ptr.offset(range.start as isize),
+            len: range.end - range.start,
+        }
+    }
+
+    /// Pointers to the elements
+    pub fn iter(&self) -> impl Iterator<Item = PhysPtr<'a, T>> + '_ {
+        (0..self.len).map(|i| self.ptr.offset(i as isize))
+    }
+}
+
+impl<T: FromBytes + AsBytes> PhysSlice<'_, T> {
+    /// Read all elements
+    pub fn read(&self) -> Result<Vec<T>, Error> {
+        let mut v: Vec<T> = (0..self.len).map(|_| T::new_zeroed()).collect();
+        let mut buf = v.as_mut_slice().as_bytes_mut();
+        let len = buf.len();
+        if len > 0 {
+            self.ptr.nettlp.dma_read(self.ptr.addr, &mut buf, len)?;
+        }
+        Ok(v)
+    }
+
+    /// Write `values` to the elements
+    ///
+    /// # Panics
+    /// Panics if `values.len()` differs from the length of the slice.
+    pub fn write(&self, values: &[T]) -> Result<(), Error> {
+        assert_eq!(values.len(), self.len, "length mismatch");
+        if values.is_empty() {
+            return Ok(());
+        }
+        self.ptr.nettlp.dma_write(self.ptr.addr, values.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci;
+    use crate::testutil::{cpld, respond};
+    use crate::transport::ChannelTransport;
+
+    #[repr(C)]
+    #[derive(AsBytes, FromBytes)]
+    struct Regs {
+        ctrl: Le32,
+        status: Be32,
+        base: Le64,
+    }
+
+    #[repr(C, packed)]
+    #[derive(AsBytes, FromBytes, PhysStruct)]
+    #[phys(size = 14)]
+    struct Packed {
+        a: u16,
+        #[phys(offset = 2)]
+        b: Be32,
+        c: Le64,
+    }
+
+    #[test]
+    fn derive_fields() {
+        let (transport, adapter) = ChannelTransport::pair();
+        let nettlp = NetTlp::with_transport(pci::Bdf::new(1, 0, 0), 0, 512, transport);
+        let fields = PhysPtr::<Packed>::new(&nettlp, 0x1000).fields();
+        assert_eq!(fields.a.addr(), 0x1000);
+        assert_eq!(fields.b.addr(), 0x1002);
+        assert_eq!(fields.c.addr(), 0x1006);
+
+        // Pretend to be the adapter: reply 4 bytes from 0x1002
+        let th = respond(adapter, |_| {
+            vec![cpld(
+                0,
+                0x02,
+                4,
+                &[0xff, 0xff, 0x12, 0x34, 0x56, 0x78, 0xff, 0xff],
+            )]
+        });
+        let b: Be32 = fields.b.read().unwrap();
+        assert_eq!(b.get(), 0x12345678);
+        drop(nettlp);
+        assert_eq!(th.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn field_read() {
+        let (transport, adapter) = ChannelTransport::pair();
+        let nettlp = NetTlp::with_transport(pci::Bdf::new(1, 0, 0), 0, 512, transport);
+        let regs = PhysPtr::<Regs>::new(&nettlp, 0x1000).offset(2);
+        let status = phys_field!(regs, status);
+        assert_eq!(status.addr(), 0x1000 + 2 * 16 + 4);
+        assert_eq!(phys_field!(regs, base).addr(), 0x1000 + 2 * 16 + 8);
+
+        // Pretend to be the adapter: reply a big-endian value
+        let th = respond(adapter, |_| {
+            vec![cpld(0, 0x24, 4, &[0x12, 0x34, 0x56, 0x78])]
+        });
+        assert_eq!(status.read().unwrap().get(), 0x12345678);
+
+        let slice = PhysSlice::<Regs>::new(&nettlp, 0x1000, 4);
+        assert_eq!(slice.index(2).addr(), regs.addr());
+        assert_eq!(slice.slice(1..3).len(), 2);
+        assert!(slice.get(4).is_none());
+
+        #[repr(C)]
+        #[derive(AsBytes, FromBytes)]
+        struct Outer {
+            tail: Le64,
+            regs: Regs,
+        }
+        let outer = PhysPtr::<Outer>::new(&nettlp, 0x2000);
+        assert_eq!(phys_field!(outer, regs.status).addr(), 0x2000 + 8 + 4);
+
+        fn is_send_sync<T: Send + Sync>(_: &T) {}
+        is_send_sync(&outer);
+        is_send_sync(&slice);
+        drop(nettlp);
+        assert_eq!(th.join().unwrap(), 1);
+    }
+}
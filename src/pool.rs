@@ -0,0 +1,244 @@
+use crate::error::Error;
+use crate::nettlp::{DmaDirection, NetTlp};
+use crate::pci;
+use crate::stats::NetTlpStats;
+use crate::transport::UdpTransport;
+
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::ops::{Deref, Range};
+use std::sync::{Condvar, Mutex};
+
+use bytes::BufMut;
+use zerocopy::{AsBytes, FromBytes};
+
+/// A set of `NetTlp` handles that can be shared among threads
+///
+/// Each handle owns a distinct tag. As the adapter sends completions to the port
+/// corresponding to the tag of the request, completions are delivered to the socket
+/// of the handle that issued the request, i.e., to the waiting caller.
+/// The handle also checks the tag, the lower address and the byte count of each completion,
+/// so a late completion of a request that has timed out is counted as stale and dropped
+/// rather than taken for the completion of the next borrower.
+/// A caller blocks until a handle (tag) becomes available.
+#[derive(Debug)]
+pub struct NetTlpPool {
+    handles: Vec<NetTlp>,
+    free: Mutex<VecDeque<usize>>,
+    available: Condvar,
+}
+
+/// A `NetTlp` handle borrowed from `NetTlpPool`
+///
+/// The handle is returned to the pool when dropped.
+/// If a request has timed out or an unexpected datagram has arrived while borrowed,
+/// completions that have already arrived are discarded before the handle is returned.
+#[derive(Debug)]
+pub struct PooledNetTlp<'a> {
+    pool: &'a NetTlpPool,
+    idx: usize,
+    errors: u64,
+}
+
+impl NetTlpPool {
+    /// Create a pool that uses `tags` for requests
+    ///
+    /// Returns `Error::InvalidTags` if `tags` is empty or if two of the tags share a port,
+    /// i.e., more than 16 tags are given for `DmaDirection::DmaIssuedByAdapter`.
+    pub fn new(
+        bdf: pci::Bdf,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        tags: Range<u8>,
+        mrrs: usize,
+        dir: DmaDirection,
+    ) -> Result<Self, Error> {
+        UdpTransport::check_tags(&tags, dir)?;
+        let handles = tags
+            .map(|tag| NetTlp::new(bdf, local_addr, remote_addr, tag, mrrs, dir))
+            .collect::<Result<Vec<_>, _>>()?;
+        let free = (0..handles.len()).collect();
+        Ok(NetTlpPool {
+            handles,
+            free: Mutex::new(free),
+            available: Condvar::new(),
+        })
+    }
+
+    /// Borrow a handle, waiting until one becomes available
+    ///
+    /// Handles are lent in turn, so that a handle that has been returned is reused last.
+    pub fn get(&self) -> PooledNetTlp<'_> {
+        let mut free = self.free.lock().unwrap();
+        loop {
+            if let Some(idx) = free.pop_front() {
+                let errors = errors(&self.handles[idx].stats());
+                return PooledNetTlp {
+                    pool: self,
+                    idx,
+                    errors,
+                };
+            }
+            free = self.available.wait(free).unwrap();
+        }
+    }
+
+    /// Read `sizeof(T)` bytes into `t` from a physical addr
+    pub fn dma_read_t<T: Sized + FromBytes + AsBytes>(
+        &self,
+        addr: u64,
+        t: &mut T,
+    ) -> Result<(), Error> {
+        self.with(|nettlp| nettlp.dma_read_t(addr, t))
+    }
+
+    /// Read `len` bytes from a physical address `addr` into `buf`
+    pub fn dma_read<T: BufMut>(&self, addr: u64, buf: &mut T, len: usize) -> Result<(), Error> {
+        self.with(|nettlp| nettlp.dma_read(addr, buf, len))
+    }
+
+    /// DMA write
+    pub fn dma_write(&self, addr: u64, buf: &[u8]) -> Result<(), Error> {
+        self.with(|nettlp| nettlp.dma_write(addr, buf))
+    }
+
+    /// Write `T` in a memory `addr`
+    pub fn dma_write_t<T: Sized + AsBytes>(&self, addr: u64, t: T) -> Result<(), Error> {
+        self.with(|nettlp| nettlp.dma_write_t(addr, t))
+    }
+
+    // Run `f` with a borrowed handle
+    fn with<R>(&self, f: impl FnOnce(&NetTlp) -> Result<R, Error>) -> Result<R, Error> {
+        f(&self.get())
+    }
+
+    fn put(&self, idx: usize) {
+        self.free.lock().unwrap().push_back(idx);
+        self.available.notify_one();
+    }
+}
+
+// Errors after which completions may be left in the socket
+fn errors(stats: &NetTlpStats) -> u64 {
+    stats.timeouts + stats.malformed + stats.stale
+}
+
+impl Deref for PooledNetTlp<'_> {
+    type Target = NetTlp;
+
+    fn deref(&self) -> &NetTlp {
+        &self.pool.handles[self.idx]
+    }
+}
+
+impl Drop for PooledNetTlp<'_> {
+    fn drop(&mut self) {
+        if errors(&self.stats()) != self.errors {
+            self.discard_pending();
+        }
+        self.pool.put(self.idx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{cpld, respond, Adapter, Mrd, UdpAdapter};
+    use crate::transport::UdpTransport;
+    use std::str::FromStr;
+
+    #[test]
+    fn shared_read() {
+        fn is_send_sync<T: Send + Sync>() {}
+        is_send_sync::<NetTlpPool>();
+
+        let local_addr = Ipv4Addr::new(127, 0, 0, 1);
+        let remote_addr = Ipv4Addr::new(127, 0, 0, 2);
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let dir = DmaDirection::DmaIssuedByLibTLP;
+        let tags = 8..10;
+
+        // Pretend to be the adapter: reply the tag as data
+        let adapters: Vec<_> = tags
+            .clone()
+            .map(|tag| {
+                let adapter = UdpAdapter::bind(remote_addr, UdpTransport::port(tag, dir));
+                respond(adapter, |tlp| {
+                    let mrd = Mrd::parse(tlp).unwrap();
+                    vec![cpld(mrd.tag, 0, 4, (mrd.tag as u32).as_bytes())]
+                })
+            })
+            .collect();
+
+        let pool = NetTlpPool::new(bdf, local_addr, remote_addr, tags, 512, dir).unwrap();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..2 {
+                        let nettlp = pool.get();
+                        let mut v = 0u32;
+                        nettlp.dma_read_t(0x1000, &mut v).unwrap();
+                        assert_eq!(v, nettlp.tag as u32);
+                    }
+                });
+            }
+        });
+        let requests: usize = adapters.into_iter().map(|th| th.join().unwrap()).sum();
+        assert_eq!(requests, 8);
+    }
+
+    #[test]
+    fn late_completion() {
+        let local_addr = Ipv4Addr::new(127, 0, 0, 1);
+        let remote_addr = Ipv4Addr::new(127, 0, 0, 2);
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let dir = DmaDirection::DmaIssuedByLibTLP;
+        let fill = |addr: u64| (addr >> 8) as u8;
+
+        // Pretend to be the adapter of tag 20, which does not reply to 0x1010 in time
+        let mut adapter = UdpAdapter::bind(remote_addr, UdpTransport::port(20, dir));
+        let pool = NetTlpPool::new(bdf, local_addr, remote_addr, 20..21, 512, dir).unwrap();
+        let mut v = 0u32;
+        assert!(matches!(
+            pool.dma_read_t(0x1010, &mut v),
+            Err(Error::Timeout)
+        ));
+
+        // Reply to 0x1010 late, then reply the second byte of the address as data
+        let mut buf = [0u8; 64];
+        let n = adapter.recv_request(&mut buf).unwrap();
+        adapter.send_reply(&Mrd::parse(&buf[6..n]).unwrap().reply(fill));
+        let th = respond(adapter, move |tlp| {
+            vec![Mrd::parse(tlp).unwrap().reply(fill)]
+        });
+
+        // The late completion is not taken for the completion of the next borrower
+        let nettlp = pool.get();
+        nettlp.dma_read_t(0x2000, &mut v).unwrap();
+        assert_eq!(v, 0x2020_2020);
+        let stats = nettlp.stats();
+        assert_eq!(stats.timeouts, 1);
+        assert_eq!(stats.stale, 1);
+        drop(nettlp);
+        drop(pool);
+        assert_eq!(th.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn aliasing_tags() {
+        let local_addr = Ipv4Addr::new(127, 0, 0, 1);
+        let remote_addr = Ipv4Addr::new(127, 0, 0, 2);
+        let bdf = pci::Bdf::from_str("01:00.0").unwrap();
+        let dir = DmaDirection::DmaIssuedByAdapter;
+
+        // Tags 0 and 16 share the port 0x4000
+        assert!(matches!(
+            NetTlpPool::new(bdf, local_addr, remote_addr, 0..17, 512, dir),
+            Err(Error::InvalidTags(tags)) if tags == (0..17)
+        ));
+        assert!(matches!(
+            NetTlpPool::new(bdf, local_addr, remote_addr, 4..4, 512, dir),
+            Err(Error::InvalidTags(_))
+        ));
+    }
+}
@@ -0,0 +1,103 @@
+use crate::error::Error;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Define `NetTlpStats` and `Counters`, its atomic counterpart
+macro_rules! stats {
+    ($($(#[doc = $doc:expr])* $name:ident,)*) => {
+        /// Statistics of a `NetTlp` handle
+        ///
+        /// Request TLPs are counted when they are built.
+        /// See `NetTlp::stats()`.
+        #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+        #[non_exhaustive]
+        pub struct NetTlpStats {
+            $($(#[doc = $doc])* pub $name: u64,)*
+        }
+
+        #[derive(Debug, Default)]
+        pub(crate) struct Counters {
+            $(pub $name: AtomicU64,)*
+        }
+
+        impl Counters {
+            pub fn snapshot(&self) -> NetTlpStats {
+                NetTlpStats {
+                    $($name: self.$name.load(Ordering::Relaxed),)*
+                }
+            }
+
+            pub fn reset(&self) {
+                $(self.$name.store(0, Ordering::Relaxed);)*
+            }
+        }
+    };
+}
+
+stats! {
+    /// Memory read request TLPs
+    mrd,
+    /// Memory write request TLPs
+    mwr,
+    /// I/O read request TLPs
+    io_rd,
+    /// I/O write request TLPs
+    io_wr,
+    /// AtomicOp request TLPs
+    atomic,
+    /// Message TLPs
+    msg,
+    /// DMA reads (`dma_read()` calls and requests of `dma_read_batch()`)
+    dma_reads,
+    /// DMA writes
+    dma_writes,
+    /// TLPs in addition to one per DMA read or write,
+    /// because of MRRS or 4 KiB boundaries
+    splits,
+    /// Completion TLPs received
+    completions,
+    /// Completions with an unsuccessful status
+    unsuccessful,
+    /// Completions with the Unsupported Request status (also counted in `unsuccessful`)
+    unsupported,
+    /// Bytes of data sent in request TLPs
+    bytes_out,
+    /// Bytes of data received in completion TLPs
+    bytes_in,
+    /// Timeouts of waiting for completions
+    timeouts,
+    /// Datagrams that are not valid completions
+    malformed,
+    /// Completions that do not match the outstanding requests,
+    /// such as late completions of a request that has timed out
+    stale,
+}
+
+impl Counters {
+    pub fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    // Count a TLP of a DMA read or write. The first TLP of each DMA counts the DMA.
+    pub fn dma_tlp(&self, is_read: bool, first: bool) {
+        let counter = match (first, is_read) {
+            (false, _) => &self.splits,
+            (true, true) => &self.dma_reads,
+            (true, false) => &self.dma_writes,
+        };
+        Counters::add(counter, 1);
+    }
+
+    // Count (and log) a timeout or a malformed datagram
+    pub fn error(&self, e: Error) -> Error {
+        match e {
+            Error::Timeout => Counters::add(&self.timeouts, 1),
+            Error::InvalidData(_) => {
+                warn!(error = %e, "malformed datagram");
+                Counters::add(&self.malformed, 1)
+            }
+            _ => {}
+        }
+        e
+    }
+}
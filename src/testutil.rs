@@ -0,0 +1,211 @@
+// Helpers for tests that pretend to be the adapter
+
+use crate::transport::{ChannelTransport, Transport};
+
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+#[cfg(all(target_os = "linux", any(feature = "af-packet", feature = "xdp")))]
+use std::process::Command;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// The responder stops after no request arrives for this duration
+const IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The adapter side of a test
+pub(crate) trait Adapter: Send + 'static {
+    /// Receive a packet, or `None` when no request arrives for `IDLE_TIMEOUT`
+    fn recv_request(&mut self, buf: &mut [u8]) -> Option<usize>;
+    /// Send a packet to where the last request came from
+    fn send_reply(&mut self, packet: &[u8]);
+}
+
+impl Adapter for ChannelTransport {
+    fn recv_request(&mut self, buf: &mut [u8]) -> Option<usize> {
+        self.recv(buf, IDLE_TIMEOUT).ok()
+    }
+
+    fn send_reply(&mut self, packet: &[u8]) {
+        // The handle may be gone when a test expects a timeout
+        let _ = self.send(packet);
+    }
+}
+
+/// A UDP socket bound to the port of the adapter and the address it replies to
+pub(crate) struct UdpAdapter {
+    socket: UdpSocket,
+    peer: Option<SocketAddr>,
+}
+
+impl UdpAdapter {
+    /// Bind the adapter side of `port` on `addr`
+    pub(crate) fn bind(addr: Ipv4Addr, port: u16) -> Self {
+        let socket = UdpSocket::bind((addr, port)).unwrap();
+        socket.set_read_timeout(Some(IDLE_TIMEOUT)).unwrap();
+        UdpAdapter { socket, peer: None }
+    }
+}
+
+impl Adapter for UdpAdapter {
+    fn recv_request(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let (n, from) = self.socket.recv_from(buf).ok()?;
+        self.peer = Some(from);
+        Some(n)
+    }
+
+    fn send_reply(&mut self, packet: &[u8]) {
+        let peer = self.peer.expect("no request received");
+        self.socket.send_to(packet, peer).unwrap();
+    }
+}
+
+/// Run `reply` for each request the adapter receives and send the packets it returns
+///
+/// `reply` takes the TLP without the NetTLP header. The thread stops when no request
+/// arrives for a while or the other side is dropped, and returns the number of requests.
+pub(crate) fn respond<A, F>(mut adapter: A, mut reply: F) -> JoinHandle<usize>
+where
+    A: Adapter,
+    F: FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; 9000];
+        let mut requests = 0;
+        while let Some(n) = adapter.recv_request(&mut buf) {
+            requests += 1;
+            for packet in reply(&buf[6..n]) {
+                adapter.send_reply(&packet);
+            }
+        }
+        requests
+    })
+}
+
+/// Build a CplD of `tag` with the NetTLP header
+///
+/// `data` is the payload in DWs, i.e., it starts at `lowaddr & !3`.
+pub(crate) fn cpld(tag: u8, lowaddr: u8, count: u16, data: &[u8]) -> Vec<u8> {
+    assert!(data.len().is_multiple_of(4) && data.len() <= 4096);
+    let dws = (data.len() / 4) as u16 & 0x3ff;
+    let count = count & 0xfff;
+    let mut p = vec![0u8; 6];
+    p.extend_from_slice(&[0x4a, 0, (dws >> 8) as u8, dws as u8, 0, 0]);
+    p.extend_from_slice(&count.to_be_bytes());
+    p.extend_from_slice(&[1, 0, tag, lowaddr]);
+    p.extend_from_slice(data);
+    p
+}
+
+/// Build a Cpl of `tag` without data and with the 3-bit `status`
+pub(crate) fn cpl(tag: u8, status: u8, count: u16) -> Vec<u8> {
+    let count = count & 0xfff;
+    let mut p = vec![0u8; 6];
+    p.extend_from_slice(&[0x0a, 0, 0, 0, 0, 0]);
+    p.extend_from_slice(&(((status as u16) << 13) | count).to_be_bytes());
+    p.extend_from_slice(&[1, 0, tag, 0]);
+    p
+}
+
+/// A memory read request parsed by the adapter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Mrd {
+    pub(crate) tag: u8,
+    /// Address of the first byte
+    pub(crate) addr: u64,
+    /// Byte count, which is 1 for a zero-length read like in its completion
+    pub(crate) count: u16,
+}
+
+impl Mrd {
+    /// Parse `tlp` if it is a memory read request
+    pub(crate) fn parse(tlp: &[u8]) -> Option<Self> {
+        let addr = match tlp[0] {
+            0x00 => u32::from_be_bytes(tlp[8..12].try_into().unwrap()) as u64,
+            0x20 => u64::from_be_bytes(tlp[8..16].try_into().unwrap()),
+            _ => return None,
+        };
+        let dws = match (((tlp[2] & 0x3) as u16) << 8) | tlp[3] as u16 {
+            0 => 1024,
+            n => n,
+        };
+        let (first_be, last_be) = (tlp[7] & 0xf, tlp[7] >> 4);
+        if first_be == 0 {
+            // Zero-length read, completed with a byte count of 1
+            return Some(Mrd {
+                tag: tlp[6],
+                addr: addr & !3,
+                count: 1,
+            });
+        }
+        let head = first_be.trailing_zeros() as u16;
+        let tail = match last_be {
+            0 => 4 - (8 - first_be.leading_zeros() as u16),
+            be => be.leading_zeros() as u16 - 4,
+        };
+        Some(Mrd {
+            tag: tlp[6],
+            addr: (addr & !3) + head as u64,
+            count: dws * 4 - head - tail,
+        })
+    }
+
+    /// Lower address field of the first completion
+    pub(crate) fn lowaddr(&self) -> u8 {
+        (self.addr & 0x7f) as u8
+    }
+
+    /// Complete the whole request in a CplD with `fill(addr)` as the byte at `addr`
+    pub(crate) fn reply(&self, fill: impl Fn(u64) -> u8) -> Vec<u8> {
+        let start = self.addr & !3;
+        let end = (self.addr + self.count as u64).next_multiple_of(4);
+        let data: Vec<u8> = (start..end).map(fill).collect();
+        cpld(self.tag, self.lowaddr(), self.count, &data)
+    }
+}
+
+/// A veth pair whose peer side has an IP address and behaves as the adapter
+///
+/// The pair is deleted when dropped.
+#[cfg(all(target_os = "linux", any(feature = "af-packet", feature = "xdp")))]
+pub(crate) struct Veth {
+    name: String,
+}
+
+#[cfg(all(target_os = "linux", any(feature = "af-packet", feature = "xdp")))]
+impl Veth {
+    /// Create `name` and its peer `peer`, which has `remote_addr`
+    pub(crate) fn new(name: &str, peer: &str, local_addr: Ipv4Addr, remote_addr: Ipv4Addr) -> Self {
+        let ip = |args: &[&str]| {
+            let s = Command::new("ip").args(args).status().unwrap();
+            assert!(s.success(), "ip {:?} failed", args);
+        };
+        ip(&["link", "add", name, "type", "veth", "peer", "name", peer]);
+        let veth = Veth {
+            name: name.to_string(),
+        };
+        ip(&["link", "set", name, "up"]);
+        ip(&["link", "set", peer, "up"]);
+        ip(&["addr", "add", &format!("{}/24", remote_addr), "dev", peer]);
+        // No one answers ARP for `local_addr`
+        let mac = std::fs::read_to_string(format!("/sys/class/net/{}/address", name)).unwrap();
+        let local = local_addr.to_string();
+        ip(&[
+            "neigh",
+            "replace",
+            &local,
+            "lladdr",
+            mac.trim(),
+            "dev",
+            peer,
+        ]);
+        veth
+    }
+}
+
+#[cfg(all(target_os = "linux", any(feature = "af-packet", feature = "xdp")))]
+impl Drop for Veth {
+    fn drop(&mut self) {
+        let _ = Command::new("ip")
+            .args(["link", "del", &self.name])
+            .status();
+    }
+}
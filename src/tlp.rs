@@ -1,3 +1,5 @@
+use crate::error::Error;
+use crate::message;
 use crate::pci;
 
 use zerocopy::AsBytes;
@@ -108,9 +110,20 @@ impl MaxValue for u64 {
 /// | MW       | 010 / 011 | 0 0000 | Memory Write Request |
 /// | Cpl      | 000       | 0 1010 | Completion w/o Data  |
 /// | CplD     | 010       | 0 1010 | Completion w/ Data   |
+/// | IORd     | 000       | 0 0010 | I/O Read Request     |
+/// | IOWr     | 010       | 0 0010 | I/O Write Request    |
+/// | FetchAdd | 010 / 011 | 0 1100 | Fetch and Add        |
+/// | Swap     | 010 / 011 | 0 1101 | Unconditional Swap   |
+/// | CAS      | 010 / 011 | 0 1110 | Compare and Swap     |
+///
+/// I/O requests always use the 3DW header and their length is 1 DW.
+///
+/// AtomicOp requests (FetchAdd, Swap, CAS) use the same header as memory requests.
+/// Their byte enables are reserved and the address must be naturally aligned
+/// to the operand size.
 ///
 // NOTE: For addresses below 4 GB, requesters must use the 32-bit format.
-#[repr(packed)]
+#[repr(C, packed)]
 #[allow(dead_code)]
 pub(crate) struct TlpMrHdr<T: ToBe + To64 + AlignDW + MaxValue + AsBytes> {
     // 1st DW
@@ -140,6 +153,16 @@ pub(crate) enum TlpType {
     Mrd,
     /// Memory Write
     Mwr,
+    /// I/O Read
+    IoRd,
+    /// I/O Write
+    IoWr,
+    /// Fetch and Add AtomicOp
+    FetchAdd,
+    /// Unconditional Swap AtomicOp
+    Swap,
+    /// Compare and Swap AtomicOp
+    Cas,
     _Unknown,
 }
 
@@ -164,7 +187,7 @@ pub(crate) enum TlpType {
 ///
 /// NOTE: data can be split into several completion TLPs
 ///
-#[repr(packed)]
+#[repr(C, packed)]
 #[derive(Clone, Copy, Debug)]
 #[allow(dead_code)]
 pub(crate) struct TlpCplHdr {
@@ -191,15 +214,101 @@ pub(crate) struct TlpCplHdr {
     pub lowaddr: u8,
 }
 
+/// Message Request Header (always 4DW)
+///
+/// +---------------+---------------+---------------+---------------+
+/// |       0       |       1       |       2       |       3       |
+/// +---------------+---------------+---------------+---------------+
+/// |7|6|5|4|3|2|1|0|7|6|5|4|3|2|1|0|7|6|5|4|3|2|1|0|7|6|5|4|3|2|1|0|
+/// +---------------+---------------+---------------+---------------+
+/// |R|Fmt|Type(10rrr)|R| TC  |   R   |T|E|Atr| R |      Length       |
+/// +---------------+---------------+---------------+---------------+
+/// |         Requeseter ID         |      Tag      | Message Code  |
+/// +---------------+---------------+---------------+---------------+
+/// |              Message specific (e.g., ID, Vendor ID)           |
+/// +---------------+---------------+---------------+---------------+
+/// |              Message specific (e.g., Vendor defined)          |
+/// +---------------+---------------+---------------+---------------+
+///
+///  FMT (3bit)
+///     - 001 : Msg (without data)
+///     - 011 : MsgD (with data)
+///
+///  rrr: routing subfield
+///
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, AsBytes)]
+#[allow(dead_code)]
+pub(crate) struct TlpMsgHdr {
+    // 1st DW
+    /// Format and Type
+    fmt_type: u8,
+    /// Trafic Class
+    tclass: u8,
+    /// Flag, Attr, Reserved, Length
+    falen: u16,
+
+    // 2nd DW
+    /// Requester ID
+    requester: u16,
+    /// Tag
+    tag: u8,
+    /// Message code
+    code: u8,
+
+    // 3rd & 4th DW
+    /// Message specific fields
+    dw2: u32,
+    dw3: u32,
+}
+
+impl TlpMsgHdr {
+    /// Create message TLP header
+    pub(crate) fn new(
+        msg: &message::Message,
+        requester: pci::Bdf,
+        tag: u8,
+        payload_len: usize,
+    ) -> Self {
+        debug_assert!(payload_len.is_multiple_of(4));
+        let fmt: u8 = if payload_len > 0 {
+            0b0111_0000
+        } else {
+            0b0011_0000
+        };
+        let fmt_type = fmt | (msg.routing() as u8);
+        let tclass: u8 = 0;
+        let falen = ((payload_len / 4) as u16) & 0x03FF;
+        let (dw2, dw3) = msg.header_dws();
+
+        TlpMsgHdr {
+            fmt_type: fmt_type.to_be(),
+            tclass: tclass.to_be(),
+            falen: falen.to_be(),
+            requester: requester.to_u16().to_be(),
+            tag: tag.to_be(),
+            code: msg.code().to_be(),
+            dw2: dw2.to_be(),
+            dw3: dw3.to_be(),
+        }
+    }
+}
+
 impl<T: ToBe + To64 + AlignDW + MaxValue + AsBytes> TlpMrHdr<T> {
+    const MR_LENGTH_MASK: u16 = 0x03FF;
+    /// A request carries or asks for at most 1024 DWs
+    const MR_MAX_LENGTH: u16 = 1024;
+
     /// Create message request TLP
+    ///
+    /// Returns `Error::InvalidLength` if the request spans more than 1024 DWs.
     pub(crate) fn new(
         tlp_type: TlpType,
         requester: pci::Bdf,
         tag: u8,
         addr: T,
         count: usize,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         let addr64 = addr.max_value() > u32::MAX as u64;
 
         // Check if using 32bit addressing when appropriate
@@ -222,14 +331,43 @@ impl<T: ToBe + To64 + AlignDW + MaxValue + AsBytes> TlpMrHdr<T> {
                     0b0100_0000
                 }
             }
+            TlpType::IoRd => {
+                debug_assert!(!addr64);
+                0b0000_0010
+            }
+            TlpType::IoWr => {
+                debug_assert!(!addr64);
+                0b0100_0010
+            }
+            TlpType::FetchAdd | TlpType::Swap | TlpType::Cas => {
+                let t = match tlp_type {
+                    TlpType::FetchAdd => 0b0000_1100,
+                    TlpType::Swap => 0b0000_1101,
+                    _ => 0b0000_1110,
+                };
+                if addr64 {
+                    0b0110_0000 | t
+                } else {
+                    0b0100_0000 | t
+                }
+            }
             _ => unimplemented!(),
         };
 
         let tclass: u8 = 0;
-        let falen = calc_length(addr.to_64(), count as u64);
-        let dw = calc_be(addr.to_64(), count as u64);
+        let length = calc_length(addr.to_64(), count as u64);
+        if length > Self::MR_MAX_LENGTH {
+            return Err(Error::InvalidLength(count));
+        }
+        // Length 0 means 1024 DW
+        let falen = length & Self::MR_LENGTH_MASK;
+        let dw = match tlp_type {
+            // Byte enables are reserved for AtomicOp requests
+            TlpType::FetchAdd | TlpType::Swap | TlpType::Cas => 0,
+            _ => calc_be(addr.to_64(), count as u64),
+        };
 
-        TlpMrHdr {
+        Ok(TlpMrHdr {
             fmt_type: fmt_type.to_be(),
             tclass: tclass.to_be(),
             falen: falen.to_be(),
@@ -237,7 +375,7 @@ impl<T: ToBe + To64 + AlignDW + MaxValue + AsBytes> TlpMrHdr<T> {
             tag: tag.to_be(),
             dw: dw.to_be(),
             addr: addr.align_dw().to_be(),
-        }
+        })
     }
 
     // #[derive(AsBytes)] does not support on types with type parameters,
@@ -249,13 +387,32 @@ impl<T: ToBe + To64 + AlignDW + MaxValue + AsBytes> TlpMrHdr<T> {
     }
 }
 
+/// Completion status
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub(crate) enum CplStatus {
+pub enum CplStatus {
+    /// Successful Completion (SC)
     Success,
+    /// Unsupported Request (UR)
     Unsupported,
+    /// Configuration Request Retry Status (CRS)
     ConfigurationRequestStatus,
+    /// Completer Abort (CA)
     CompleterAbort,
-    Unknown,
+    /// Reserved status, with the raw 3-bit value of the Completion Status field
+    Unknown(u8),
+}
+
+impl std::fmt::Display for CplStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            CplStatus::Success => "SC",
+            CplStatus::Unsupported => "UR",
+            CplStatus::ConfigurationRequestStatus => "CRS",
+            CplStatus::CompleterAbort => "CA",
+            CplStatus::Unknown(bits) => return write!(f, "unknown ({:#05b})", bits),
+        };
+        f.write_str(s)
+    }
 }
 
 impl From<u16> for CplStatus {
@@ -265,7 +422,7 @@ impl From<u16> for CplStatus {
             0x2000 => CplStatus::Unsupported,
             0x4000 => CplStatus::ConfigurationRequestStatus,
             0x8000 => CplStatus::CompleterAbort,
-            _ => CplStatus::Unknown,
+            _ => CplStatus::Unknown((n >> 13) as u8 & 0x7),
         }
     }
 }
@@ -293,16 +450,34 @@ impl TlpCplHdr {
         self.length() == (((self.lowaddr as u16 & 0x3) + self.count() + 3) >> 2)
     }
 
+    pub(crate) fn completer(&self) -> pci::Bdf {
+        pci::Bdf::from_u16(self.completer.to_be())
+    }
+
     pub(crate) fn status(&self) -> CplStatus {
         CplStatus::from(self.stcnt.to_be() & TlpCplHdr::CPL_STATUS_MASK)
     }
 
+    // Length 0 means 1024 DW
     pub(crate) fn length(&self) -> u16 {
-        self.falen.to_be() & TlpCplHdr::CPL_LENGTH_MASK
+        match self.falen.to_be() & TlpCplHdr::CPL_LENGTH_MASK {
+            0 => 1024,
+            n => n,
+        }
     }
 
+    // Byte count 0 means 4096 bytes
     pub(crate) fn count(&self) -> u16 {
-        self.stcnt.to_be() & TlpCplHdr::CPL_COUNT_MASK
+        match self.stcnt.to_be() & TlpCplHdr::CPL_COUNT_MASK {
+            0 => 4096,
+            n => n,
+        }
+    }
+
+    // Number of valid bytes in the payload, which starts at the lower address in the first DW
+    pub(crate) fn data_len(&self) -> usize {
+        let offset = (self.lowaddr & 0x3) as usize;
+        std::cmp::min(self.count() as usize, self.length() as usize * 4 - offset)
     }
 }
 
@@ -319,6 +494,8 @@ impl TlpCplHdr {
 //  1st BE: 0001b
 //  last BE: 1100b
 //
+// A zero-length request (count 0) is 1 DW long with no bytes enabled.
+//
 fn calc_be(addr: u64, count: u64) -> u8 {
     let lastbe = calc_lastbe(addr, count);
     let firstbe = calc_firstbe(addr, count);
@@ -326,6 +503,9 @@ fn calc_be(addr: u64, count: u64) -> u8 {
 }
 
 fn calc_lastbe(addr: u64, count: u64) -> u8 {
+    if count == 0 {
+        return 0;
+    }
     let start = (addr >> 2) << 2;
     let end = addr + count;
     let end_start_ = if (end & 0x3) == 0 {
@@ -356,6 +536,9 @@ fn calc_firstbe(addr: u64, count: u64) -> u8 {
 
 // Calculate how many DWs are read / written
 fn calc_length(addr: u64, count: u64) -> u16 {
+    if count == 0 {
+        return 1;
+    }
     let start = addr & !0x3;
     let end = addr + count;
     let len = ((end - start) >> 2) as u16;
@@ -365,3 +548,190 @@ fn calc_length(addr: u64, count: u64) -> u16 {
         len
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atomic_header() {
+        let bdf = pci::Bdf::new(1, 0, 0);
+
+        let h = TlpMrHdr::new(TlpType::FetchAdd, bdf, 3, 0x1000u32, 4).unwrap();
+        assert_eq!(h.as_bytes(), [0x4c, 0, 0, 1, 1, 0, 3, 0, 0, 0, 0x10, 0]);
+
+        let h = TlpMrHdr::new(TlpType::Swap, bdf, 3, 0x1_0000_0000u64, 8).unwrap();
+        assert_eq!(
+            h.as_bytes(),
+            [0x6d, 0, 0, 2, 1, 0, 3, 0, 0, 0, 0, 1, 0, 0, 0, 0]
+        );
+
+        // 128bit CAS carries compare and swap values (8 DWs)
+        let h = TlpMrHdr::new(TlpType::Cas, bdf, 3, 0x2000u32, 32).unwrap();
+        assert_eq!(h.as_bytes(), [0x4e, 0, 0, 8, 1, 0, 3, 0, 0, 0, 0x20, 0]);
+    }
+
+    #[test]
+    fn io_header() {
+        let bdf = pci::Bdf::new(1, 0, 0);
+
+        let h = TlpMrHdr::new(TlpType::IoRd, bdf, 0, 0x3f9u32, 1).unwrap();
+        assert_eq!(
+            h.as_bytes(),
+            [0x02, 0, 0, 1, 1, 0, 0, 0x02, 0, 0, 0x03, 0xf8]
+        );
+
+        let h = TlpMrHdr::new(TlpType::IoWr, bdf, 0, 0xcfcu32, 4).unwrap();
+        assert_eq!(
+            h.as_bytes(),
+            [0x42, 0, 0, 1, 1, 0, 0, 0x0f, 0, 0, 0x0c, 0xfc]
+        );
+    }
+
+    #[test]
+    fn message_header() {
+        use message::{IntxPin, Message, MsgRouting};
+        let bdf = pci::Bdf::new(1, 0, 0);
+
+        let msg = Message::AssertIntx(IntxPin::B);
+        let h = TlpMsgHdr::new(&msg, bdf, 0, 0);
+        assert_eq!(
+            h.as_bytes(),
+            [0x34, 0, 0, 0, 1, 0, 0, 0x21, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        let msg = Message::VendorDefined {
+            type1: true,
+            routing: MsgRouting::Id,
+            target: pci::Bdf::new(2, 0, 1),
+            vendor_id: 0x1234,
+            vendor_data: 0xdeadbeef,
+            payload: vec![0; 8],
+        };
+        let h = TlpMsgHdr::new(&msg, bdf, 0, 8);
+        assert_eq!(
+            h.as_bytes(),
+            [0x72, 0, 0, 2, 1, 0, 0, 0x7f, 2, 1, 0x12, 0x34, 0xde, 0xad, 0xbe, 0xef]
+        );
+    }
+
+    // Length (DW), 1st BE and last BE of a request of `count` bytes, computed byte by byte
+    fn model(addr: u64, count: u64) -> (u16, u8, u8) {
+        if count == 0 {
+            return (1, 0, 0);
+        }
+        let bytes = addr..addr + count;
+        let be = |dw: u64| {
+            (0..4)
+                .filter(|i| bytes.contains(&(dw * 4 + i)))
+                .fold(0u8, |be, i| be | (1 << i))
+        };
+        let first_dw = addr / 4;
+        let last_dw = (addr + count - 1) / 4;
+        let lastbe = if first_dw == last_dw { 0 } else { be(last_dw) };
+        ((last_dw - first_dw + 1) as u16, be(first_dw), lastbe)
+    }
+
+    #[test]
+    fn zero_length_read() {
+        let bdf = pci::Bdf::new(1, 0, 0);
+        for addr in [0x1000u32, 0x1003] {
+            let h = TlpMrHdr::new(TlpType::Mrd, bdf, 0, addr, 0).unwrap();
+            assert_eq!(h.as_bytes(), [0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0x10, 0]);
+        }
+    }
+
+    #[test]
+    fn max_request() {
+        let bdf = pci::Bdf::new(1, 0, 0);
+        let h = TlpMrHdr::new(TlpType::Mrd, bdf, 0, 0x1000u32, 4096).unwrap();
+        assert_eq!(h.as_bytes(), [0, 0, 0, 0, 1, 0, 0, 0xff, 0, 0, 0x10, 0]);
+        // 4096 bytes from an unaligned address span 1025 DWs
+        assert!(matches!(
+            TlpMrHdr::new(TlpType::Mrd, bdf, 0, 0x1001u32, 4096),
+            Err(Error::InvalidLength(4096))
+        ));
+    }
+
+    #[test]
+    fn unknown_status() {
+        let status = CplStatus::from(0xC000);
+        assert_eq!(status, CplStatus::Unknown(0b110));
+        assert_eq!(status.to_string(), "unknown (0b110)");
+    }
+
+    #[test]
+    fn max_completion() {
+        // Length 0 (1024 DW) and byte count 0 (4096 bytes)
+        let cpl = TlpCplHdr {
+            fmt_type: TlpCplHdr::CPL_FMT_TYPE_CPL_WITH_DATA,
+            tclass: 0,
+            falen: 0,
+            completer: 0,
+            stcnt: 0,
+            requester: 0,
+            tag: 0,
+            lowaddr: 0,
+        };
+        assert_eq!(cpl.length(), 1024);
+        assert_eq!(cpl.count(), 4096);
+        assert_eq!(cpl.data_len(), 4096);
+        assert!(cpl.is_last_tlp());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn mr_header_matches_model(addr in 0..u64::MAX - 0x1000, count in 0..=0x1000u64) {
+            let bdf = pci::Bdf::new(1, 0, 0);
+            let h = if addr <= u32::MAX as u64 {
+                TlpMrHdr::new(TlpType::Mrd, bdf, 0, addr as u32, count as usize).map(|h| h.as_bytes().to_vec())
+            } else {
+                TlpMrHdr::new(TlpType::Mrd, bdf, 0, addr, count as usize).map(|h| h.as_bytes().to_vec())
+            };
+            let (length, firstbe, lastbe) = model(addr, count);
+            if length > 1024 {
+                proptest::prop_assert!(matches!(h, Err(Error::InvalidLength(_))));
+                return Ok(());
+            }
+            let bytes = h.unwrap();
+            let field = u16::from_be_bytes([bytes[2], bytes[3]]);
+            // Length 0 means 1024 DW
+            proptest::prop_assert_eq!(field, length % 1024);
+            proptest::prop_assert_eq!(bytes[7] & 0xF, firstbe);
+            proptest::prop_assert_eq!(bytes[7] >> 4, lastbe);
+            let encoded = if addr <= u32::MAX as u64 {
+                u32::from_be_bytes(bytes[8..12].try_into().unwrap()) as u64
+            } else {
+                u64::from_be_bytes(bytes[8..16].try_into().unwrap())
+            };
+            proptest::prop_assert_eq!(encoded, addr & !0x3);
+        }
+
+        #[test]
+        fn last_completion(
+            addr in 0..0x10000u64,
+            count in 1..=0x1000u64,
+            rcb in proptest::sample::select(vec![64u64, 128]),
+        ) {
+            // Split the completion at every RCB boundary, which is the most a completer may do
+            let end = addr + count;
+            let mut p = addr;
+            while p < end {
+                let next = std::cmp::min((p / rcb + 1) * rcb, end);
+                let length = (next.div_ceil(4) - p / 4) as u16;
+                let cpl = TlpCplHdr {
+                    fmt_type: TlpCplHdr::CPL_FMT_TYPE_CPL_WITH_DATA,
+                    tclass: 0,
+                    falen: (length & TlpCplHdr::CPL_LENGTH_MASK).to_be(),
+                    completer: 0,
+                    stcnt: (((end - p) as u16) & TlpCplHdr::CPL_COUNT_MASK).to_be(),
+                    requester: 0,
+                    tag: 0,
+                    lowaddr: (p & 0x7F) as u8,
+                };
+                proptest::prop_assert_eq!(cpl.is_last_tlp(), next == end, "completion at {:#x}", p);
+                p = next;
+            }
+        }
+    }
+}
@@ -0,0 +1,38 @@
+// Wrappers of `tracing` macros that expand to nothing without the `tracing` feature
+
+// Enter a debug-level span until the end of the enclosing block
+#[cfg(feature = "tracing")]
+macro_rules! dma_span {
+    ($name:literal, $($field:tt)*) => {
+        let _span = tracing::debug_span!($name, $($field)*).entered();
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! dma_span {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        tracing::trace!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        tracing::warn!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! warn {
+    ($($arg:tt)*) => {};
+}
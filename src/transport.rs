@@ -0,0 +1,425 @@
+use crate::error::Error;
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+use crate::mmsg;
+use crate::nettlp::DmaDirection;
+
+use bytes::buf::UninitSlice;
+
+use std::net::Ipv4Addr;
+use std::net::UdpSocket;
+use std::ops::Range;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const EAGAIN: i32 = 11;
+
+/// Datagram transport of NetTLP packets
+///
+/// A transport carries NetTLP packets (NetTLP header + TLP) between `NetTlp` and the adapter.
+pub trait Transport: std::fmt::Debug + Send + Sync {
+    /// Send a datagram
+    fn send(&self, packet: &[u8]) -> Result<(), Error>;
+
+    /// Receive a datagram into `buf` and return its size
+    ///
+    /// Returns `Error::Timeout` if no datagram arrives within `timeout`.
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error>;
+
+    /// Receive a datagram whose first `hdr.len()` bytes go to `hdr` and the rest to `payload`
+    ///
+    /// Returns the number of bytes stored in `hdr` and `payload` in total.
+    /// This lets a completion payload land in the caller's buffer without an intermediate copy
+    /// where the transport supports it.
+    /// `UdpTransport` on Unix and `PacketTransport` without a ring receive the payload
+    /// into `payload` directly, while a `PacketTransport` ring and `XdpTransport` copy it
+    /// from the frame shared with the kernel. None of them allocates.
+    /// The default implementation receives the datagram into a temporary buffer,
+    /// which is the case for `UdpTransport` on other platforms.
+    fn recv_split(
+        &self,
+        hdr: &mut [u8],
+        payload: &mut UninitSlice,
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let mut buf = vec![0u8; hdr.len() + payload.len()];
+        let n = self.recv(&mut buf, timeout)?;
+        Ok(split_copy(&buf[..n], hdr, payload))
+    }
+
+    /// Send several datagrams
+    fn send_batch(&self, packets: &[&[u8]]) -> Result<(), Error> {
+        for packet in packets {
+            self.send(packet)?;
+        }
+        Ok(())
+    }
+
+    /// Receive one or more datagrams into `bufs` and return the size of each datagram
+    ///
+    /// This waits for the first datagram at most `timeout`.
+    fn recv_batch(&self, bufs: &mut [Vec<u8>], timeout: Duration) -> Result<Vec<usize>, Error> {
+        let n = self.recv(&mut bufs[0], timeout)?;
+        Ok(vec![n])
+    }
+
+    /// Discard datagrams that have been already received
+    fn discard_pending(&self) {}
+
+    /// This transport as a `UdpTransport`, if it is one
+    fn as_udp(&self) -> Option<&UdpTransport> {
+        None
+    }
+}
+
+/// UDP transport to the NetTLP adapter
+///
+/// The adapter sends completions to the port corresponding to the tag of the request,
+/// so the transport has a socket for each tag it is opened for.
+/// A request is sent through the socket of its tag, and datagrams are received
+/// from whichever socket has one.
+#[derive(Debug)]
+pub struct UdpTransport {
+    pub remote_addr: Ipv4Addr,
+    pub local_addr: Ipv4Addr,
+    pub dir: DmaDirection,
+    /// Tag of the first socket
+    tag: u8,
+    /// Sockets of `tag`, `tag + 1`, ...
+    sockets: Vec<UdpSocket>,
+    timeout: Mutex<Option<Duration>>,
+}
+
+impl UdpTransport {
+    /* TODO: implement
+    /// Port for messaging API
+    const NETTLP_MSG_PORT: u16 = 0x2FFF; // 12287
+    */
+    /// Base port for DmaIssuedByLibTLP mode
+    pub(crate) const NETTLP_LIBTLP_PORT_BASE: u16 = 0x3000;
+    /// Base port for DmaIssuedByAdapter mode
+    const NETTLP_ADAPTER_PORT_BASE: u16 = 0x4000;
+    /// Offset of the tag in a request datagram (NetTLP header + TLP header byte 6)
+    const TAG_OFFSET: usize = 6 + 6;
+
+    /// Open a UDP socket for `tag`
+    ///
+    /// The adapter sends completions to the port corresponding to the tag of the request.
+    pub fn new(
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        tag: u8,
+        dir: DmaDirection,
+    ) -> Result<Self, Error> {
+        UdpTransport::open(local_addr, remote_addr, tag, 1, dir)
+    }
+
+    /// Open a UDP socket for each of `tags`
+    ///
+    /// Returns `Error::InvalidTags` if `tags` is empty or two of them share a port,
+    /// i.e., `tags` has more than 16 tags in `DmaIssuedByAdapter` mode.
+    /// Only one tag is supported on platforms other than Unix.
+    pub fn with_tags(
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        tags: Range<u8>,
+        dir: DmaDirection,
+    ) -> Result<Self, Error> {
+        UdpTransport::check_tags(&tags, dir)?;
+        if cfg!(not(unix)) && tags.len() > 1 {
+            return Err(Error::InvalidTags(tags));
+        }
+        UdpTransport::open(local_addr, remote_addr, tags.start, tags.len(), dir)
+    }
+
+    fn open(
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        tag: u8,
+        n: usize,
+        dir: DmaDirection,
+    ) -> Result<Self, Error> {
+        let sockets = (0..n)
+            .map(|i| {
+                let port = UdpTransport::port(tag + i as u8, dir);
+                let socket = UdpSocket::bind((local_addr, port))?;
+                socket.connect((remote_addr, port))?;
+                Ok(socket)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(UdpTransport {
+            remote_addr,
+            local_addr,
+            dir,
+            tag,
+            sockets,
+            timeout: Mutex::new(None),
+        })
+    }
+
+    /// UDP port used for `tag`
+    pub(crate) fn port(tag: u8, dir: DmaDirection) -> u16 {
+        match dir {
+            DmaDirection::DmaIssuedByLibTLP => UdpTransport::NETTLP_LIBTLP_PORT_BASE + (tag as u16),
+            DmaDirection::DmaIssuedByAdapter => {
+                UdpTransport::NETTLP_ADAPTER_PORT_BASE + ((tag & 0x0F) as u16)
+            }
+        }
+    }
+
+    // Completions of `tags` must arrive at distinct ports to be told apart
+    pub(crate) fn check_tags(tags: &Range<u8>, dir: DmaDirection) -> Result<(), Error> {
+        let ports = match dir {
+            DmaDirection::DmaIssuedByLibTLP => 0x100,
+            DmaDirection::DmaIssuedByAdapter => 0x10,
+        };
+        if tags.is_empty() || tags.len() > ports {
+            return Err(Error::InvalidTags(tags.clone()));
+        }
+        Ok(())
+    }
+
+    /// UDP socket of the first tag of this transport
+    pub fn socket(&self) -> &UdpSocket {
+        &self.sockets[0]
+    }
+
+    /// UDP sockets of all tags of this transport, in the order of the tags
+    pub fn sockets(&self) -> &[UdpSocket] {
+        &self.sockets
+    }
+
+    // Socket of the tag of a request datagram
+    fn socket_of(&self, packet: &[u8]) -> &UdpSocket {
+        packet
+            .get(UdpTransport::TAG_OFFSET)
+            .and_then(|tag| self.sockets.get(tag.wrapping_sub(self.tag) as usize))
+            .unwrap_or(&self.sockets[0])
+    }
+
+    // Avoid calling setsockopt(2) for every datagram
+    fn set_timeout(&self, timeout: Duration) -> Result<(), Error> {
+        let mut current = self.timeout.lock().unwrap();
+        if *current != Some(timeout) {
+            for socket in &self.sockets {
+                socket.set_read_timeout(Some(timeout))?;
+            }
+            *current = Some(timeout);
+        }
+        Ok(())
+    }
+
+    // Wait for a socket to receive from.
+    // With several sockets, poll(2) picks the one that has a datagram;
+    // the read timeout still bounds the wait if another thread takes it first.
+    fn readable(&self, timeout: Duration) -> Result<&UdpSocket, Error> {
+        self.set_timeout(timeout)?;
+        if self.sockets.len() == 1 {
+            return Ok(&self.sockets[0]);
+        }
+        self.poll(timeout)
+    }
+
+    #[cfg(unix)]
+    fn poll(&self, timeout: Duration) -> Result<&UdpSocket, Error> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut pfds: Vec<libc::pollfd> = self
+            .sockets
+            .iter()
+            .map(|s| libc::pollfd {
+                fd: s.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let ms = std::cmp::min(timeout.as_micros().div_ceil(1000), i32::MAX as u128) as i32;
+        loop {
+            let r = unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as _, ms) };
+            if r == 0 {
+                return Err(Error::Timeout);
+            }
+            if r < 0 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e.into());
+            }
+            let i = pfds.iter().position(|p| p.revents != 0).unwrap_or(0);
+            return Ok(&self.sockets[i]);
+        }
+    }
+
+    // `with_tags()` opens only one socket
+    #[cfg(not(unix))]
+    fn poll(&self, _timeout: Duration) -> Result<&UdpSocket, Error> {
+        Ok(&self.sockets[0])
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&self, packet: &[u8]) -> Result<(), Error> {
+        self.socket_of(packet).send(packet)?;
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        self.readable(timeout)?.recv(buf).map_err(recv_error)
+    }
+
+    // Scatter the datagram so that the payload lands in `payload` directly
+    #[cfg(unix)]
+    fn recv_split(
+        &self,
+        hdr: &mut [u8],
+        payload: &mut UninitSlice,
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        use std::os::unix::io::AsRawFd;
+
+        let socket = self.readable(timeout)?;
+        let mut iovs = [
+            libc::iovec {
+                iov_base: hdr.as_mut_ptr() as *mut _,
+                iov_len: hdr.len(),
+            },
+            libc::iovec {
+                iov_base: payload.as_mut_ptr() as *mut _,
+                iov_len: payload.len(),
+            },
+        ];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = iovs.as_mut_ptr();
+        msg.msg_iovlen = iovs.len() as _;
+        let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+        if n < 0 {
+            return Err(recv_error(std::io::Error::last_os_error()));
+        }
+        Ok(n as usize)
+    }
+
+    // Requests are sent in runs of the same tag, as each tag has its own socket
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    fn send_batch(&self, packets: &[&[u8]]) -> Result<(), Error> {
+        for run in packets.chunk_by(|a, b| std::ptr::eq(self.socket_of(a), self.socket_of(b))) {
+            mmsg::send(self.socket_of(run[0]), run)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    fn recv_batch(&self, bufs: &mut [Vec<u8>], timeout: Duration) -> Result<Vec<usize>, Error> {
+        mmsg::recv(self.readable(timeout)?, bufs).map_err(recv_error)
+    }
+
+    fn discard_pending(&self) {
+        let mut buf = [0u8; 64];
+        for socket in &self.sockets {
+            if socket.set_nonblocking(true).is_ok() {
+                while socket.recv(&mut buf).is_ok() {}
+                let _ = socket.set_nonblocking(false);
+            }
+        }
+    }
+
+    fn as_udp(&self) -> Option<&UdpTransport> {
+        Some(self)
+    }
+}
+
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for UdpTransport {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.socket().as_raw_fd()
+    }
+}
+
+// Copy `datagram` into `hdr` and `payload` (truncated) and return the number of bytes copied
+pub(crate) fn split_copy(datagram: &[u8], hdr: &mut [u8], payload: &mut UninitSlice) -> usize {
+    let h = std::cmp::min(datagram.len(), hdr.len());
+    hdr[..h].copy_from_slice(&datagram[..h]);
+    let p = std::cmp::min(datagram.len() - h, payload.len());
+    payload[..p].copy_from_slice(&datagram[h..h + p]);
+    h + p
+}
+
+fn recv_error(e: std::io::Error) -> Error {
+    if errno::errno().0 == EAGAIN {
+        Error::Timeout
+    } else {
+        Error::from(e)
+    }
+}
+
+/// In-process transport
+///
+/// `ChannelTransport::pair()` creates two connected endpoints.
+/// This is useful for testing, where one endpoint pretends to be the adapter.
+#[derive(Debug)]
+pub struct ChannelTransport {
+    tx: Mutex<mpsc::Sender<Vec<u8>>>,
+    rx: Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl ChannelTransport {
+    /// Create a pair of connected endpoints
+    pub fn pair() -> (ChannelTransport, ChannelTransport) {
+        let (tx0, rx0) = mpsc::channel();
+        let (tx1, rx1) = mpsc::channel();
+        let a = ChannelTransport {
+            tx: Mutex::new(tx0),
+            rx: Mutex::new(rx1),
+        };
+        let b = ChannelTransport {
+            tx: Mutex::new(tx1),
+            rx: Mutex::new(rx0),
+        };
+        (a, b)
+    }
+
+    fn recv_packet(&self, timeout: Duration) -> Result<Vec<u8>, Error> {
+        self.rx
+            .lock()
+            .unwrap()
+            .recv_timeout(timeout)
+            .map_err(|e| match e {
+                mpsc::RecvTimeoutError::Timeout => Error::Timeout,
+                mpsc::RecvTimeoutError::Disconnected => {
+                    Error::Io(std::io::Error::from(std::io::ErrorKind::NotConnected))
+                }
+            })
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&self, packet: &[u8]) -> Result<(), Error> {
+        self.tx
+            .lock()
+            .unwrap()
+            .send(packet.to_vec())
+            .map_err(|_| Error::Io(std::io::Error::from(std::io::ErrorKind::NotConnected)))
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let packet = self.recv_packet(timeout)?;
+        // Truncate the datagram like a UDP socket does
+        let n = std::cmp::min(packet.len(), buf.len());
+        buf[..n].copy_from_slice(&packet[..n]);
+        Ok(n)
+    }
+
+    fn recv_split(
+        &self,
+        hdr: &mut [u8],
+        payload: &mut UninitSlice,
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let packet = self.recv_packet(timeout)?;
+        Ok(split_copy(&packet, hdr, payload))
+    }
+
+    fn discard_pending(&self) {
+        while self.rx.lock().unwrap().try_recv().is_ok() {}
+    }
+}
@@ -0,0 +1,687 @@
+// io_uring based DMA engine
+//
+// Many send and recv operations are kept in flight on the sockets of the tags,
+// so that MRd and CplD TLPs are pipelined without a system call per TLP.
+// References
+//   - https://kernel.dk/io_uring.pdf
+
+use crate::error::Error;
+use crate::nettlp::{self, DmaDirection, InflightMrd, NetTlp, NetTlpHdr};
+use crate::pci;
+use crate::stats::Counters;
+use crate::tlp;
+use crate::transport::UdpTransport;
+
+use io_uring::{opcode, types, IoUring};
+
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// A DMA request of `UringEngine`
+#[derive(Clone, Debug)]
+pub enum DmaRequest {
+    /// Read `len` bytes from `addr`
+    Read { addr: u64, len: usize },
+    /// Write `data` to `addr`
+    Write { addr: u64, data: Vec<u8> },
+}
+
+/// The result of a `DmaRequest`
+#[derive(Debug)]
+pub struct DmaCompletion {
+    /// The id returned by `UringEngine::submit()`
+    pub id: u64,
+    /// Data read by a read request, or the buffer given to a write request
+    pub result: Result<Vec<u8>, Error>,
+}
+
+// A request being processed
+#[derive(Debug)]
+struct Request {
+    id: u64,
+    /// Read data or data to write
+    data: Vec<u8>,
+    /// Bytes not completed yet
+    remaining: usize,
+    error: Option<Error>,
+}
+
+// A TLP to be sent
+#[derive(Debug)]
+enum Chunk {
+    Read(InflightMrd),
+    Write {
+        req: usize,
+        offset: usize,
+        addr: u64,
+        len: usize,
+    },
+}
+
+// A datagram being sent
+#[derive(Debug)]
+struct Sending {
+    req: usize,
+    /// Tag of a read request
+    tag: u8,
+    /// Length of the written data (0 for a read request)
+    write_len: usize,
+    packet: bytes::BytesMut,
+}
+
+/// DMA engine that pipelines requests with io_uring
+///
+/// Requests are split into TLPs in the same way as `NetTlp::dma_read()` and `NetTlp::dma_write()`.
+/// Like `NetTlp::dma_read_batch()`, a read request TLP is kept in flight for each tag
+/// of the engine, and its completions are identified by the tag.
+/// When no completion arrives in a while, the read requests in flight fail with
+/// `Error::Timeout` and their tags are reused last. Their late completions are counted
+/// as stale (see `NetTlpStats::stale`) and dropped.
+///
+/// ```no_run
+/// use libtlp::{DmaDirection, DmaRequest, UringEngine};
+/// use libtlp::pci::Bdf;
+/// use std::net::Ipv4Addr;
+///
+/// let mut engine = UringEngine::new(
+///     Bdf::new(1, 0, 0),
+///     Ipv4Addr::new(192, 168, 10, 3),
+///     Ipv4Addr::new(192, 168, 10, 1),
+///     0..32,
+///     512,
+///     DmaDirection::DmaIssuedByLibTLP,
+/// )
+/// .unwrap();
+/// let ids = engine.submit((0..64).map(|i| DmaRequest::Read {
+///     addr: 0x100000 + i * 4096,
+///     len: 4096,
+/// }));
+/// while engine.pending() > 0 {
+///     for cpl in engine.complete().unwrap() {
+///         println!("{}: {:?}", cpl.id, cpl.result.map(|data| data.len()));
+///     }
+/// }
+/// # let _ = ids;
+/// ```
+pub struct UringEngine {
+    nettlp: NetTlp,
+    /// Sockets of the tags
+    fds: Vec<RawFd>,
+    ring: IoUring,
+    next_id: u64,
+    /// Requests indexed by `InflightMrd::req`
+    reqs: Vec<Option<Request>>,
+    free_reqs: Vec<usize>,
+    /// TLPs not sent yet
+    queue: VecDeque<Chunk>,
+    /// Read request TLPs waiting for completions
+    inflight: Vec<InflightMrd>,
+    /// Tags not used by `inflight`, which are reused in the order they are freed
+    free_tags: VecDeque<u8>,
+    /// Datagrams being sent indexed by user_data
+    sending: Vec<Option<Sending>>,
+    /// Receive buffers of `fds`
+    recv_bufs: Vec<Vec<u8>>,
+    /// Number of operations owned by the kernel
+    outstanding: usize,
+    completed: Vec<DmaCompletion>,
+}
+
+impl UringEngine {
+    /// The number of submission queue entries
+    const RING_ENTRIES: u32 = 256;
+    /// user_data of recv operations (the lower bits are the buffer index)
+    const RECV: u64 = 1 << 63;
+    /// user_data of cancel operations
+    const CANCEL: u64 = u64::MAX;
+
+    /// Create an engine that talks with the adapter over UDP with `tags`
+    ///
+    /// Returns `Error::InvalidTags` if the completions of `tags` cannot be told apart
+    /// (see `UdpTransport::with_tags()`).
+    pub fn new(
+        bdf: pci::Bdf,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        tags: Range<u8>,
+        mrrs: usize,
+        dir: DmaDirection,
+    ) -> Result<Self, Error> {
+        let transport = UdpTransport::with_tags(local_addr, remote_addr, tags.clone(), dir)?;
+        let fds: Vec<_> = transport.sockets().iter().map(|s| s.as_raw_fd()).collect();
+        let nettlp = NetTlp::with_transport_tags(bdf, tags, mrrs, transport);
+        let ring = IoUring::new(UringEngine::RING_ENTRIES)?;
+
+        let nh_size = std::mem::size_of::<NetTlpHdr>();
+        let cpl_size = std::mem::size_of::<tlp::TlpCplHdr>();
+        // A completion contains at most 4k bytes (+ extra bytes for non DW-aligned data)
+        let bufsize = nh_size + cpl_size + std::cmp::min(mrrs, 0x1000) + 8;
+        let mut engine = UringEngine {
+            inflight: Vec::with_capacity(fds.len()),
+            free_tags: nettlp.tags().collect(),
+            recv_bufs: vec![vec![0; bufsize]; fds.len()],
+            nettlp,
+            fds,
+            ring,
+            next_id: 0,
+            reqs: vec![],
+            free_reqs: vec![],
+            queue: VecDeque::new(),
+            sending: vec![],
+            outstanding: 0,
+            completed: vec![],
+        };
+        for i in 0..engine.recv_bufs.len() {
+            engine.post_recv(i)?;
+        }
+        Ok(engine)
+    }
+
+    /// The `NetTlp` handle used by this engine
+    ///
+    /// It can be used for other operations while no request is pending.
+    pub fn nettlp(&self) -> &NetTlp {
+        &self.nettlp
+    }
+
+    /// Queue `reqs` and return their ids
+    ///
+    /// Requests are sent by `complete()`.
+    pub fn submit(&mut self, reqs: impl IntoIterator<Item = DmaRequest>) -> Vec<u64> {
+        use std::cmp::min;
+
+        let mrrs = self.nettlp.mrrs;
+        reqs.into_iter()
+            .map(|r| {
+                let id = self.next_id;
+                self.next_id += 1;
+                let (addr, data, is_read) = match r {
+                    DmaRequest::Read { addr, len } => (addr, vec![0; len], true),
+                    DmaRequest::Write { addr, data } => {
+                        assert!(
+                            addr & 0x3 == 0 && data.len().is_multiple_of(4),
+                            "non DW-aligned requests are not implemented"
+                        );
+                        (addr, data, false)
+                    }
+                };
+                let total_len = data.len();
+                let req = self.alloc_req(Request {
+                    id,
+                    data,
+                    remaining: total_len,
+                    error: None,
+                });
+
+                let mut offset = 0;
+                while offset < total_len {
+                    let p = addr + offset as u64;
+                    let max_len = 0x1000 - (p & 0xFFF) as usize;
+                    let len = min(min(total_len - offset, mrrs), max_len);
+                    self.nettlp.stats.dma_tlp(is_read, offset == 0);
+                    self.queue.push_back(if is_read {
+                        Chunk::Read(InflightMrd {
+                            tag: 0,
+                            req,
+                            offset,
+                            addr: p,
+                            len,
+                            received: 0,
+                        })
+                    } else {
+                        Chunk::Write {
+                            req,
+                            offset,
+                            addr: p,
+                            len,
+                        }
+                    });
+                    offset += len;
+                }
+                if total_len == 0 {
+                    self.finish(req);
+                }
+                id
+            })
+            .collect()
+    }
+
+    /// Number of requests not completed yet
+    pub fn pending(&self) -> usize {
+        self.reqs.iter().filter(|r| r.is_some()).count()
+    }
+
+    /// Send queued requests and wait until at least one request completes
+    ///
+    /// Returns the completed requests (an empty `Vec` if no request is pending).
+    /// A request that fails with a completion status or `Error::Timeout` (no completion
+    /// in a while) is returned as an erroneous `DmaCompletion`,
+    /// while an I/O error or a malformed datagram is returned as `Err`.
+    pub fn complete(&mut self) -> Result<Vec<DmaCompletion>, Error> {
+        loop {
+            self.fill()?;
+            if !self.completed.is_empty() || self.pending() == 0 {
+                return Ok(std::mem::take(&mut self.completed));
+            }
+
+            let timeout = NetTlp::LIBTLP_CPL_TIMEOUT;
+            let ts = types::Timespec::new()
+                .sec(timeout.as_secs())
+                .nsec(timeout.subsec_nanos());
+            let args = types::SubmitArgs::new().timespec(&ts);
+            if let Err(e) = self.ring.submitter().submit_with_args(1, &args) {
+                match e.raw_os_error() {
+                    Some(libc::ETIME) if self.ring.completion().is_empty() => {
+                        let e = self.nettlp.stats.error(Error::Timeout);
+                        if self.inflight.is_empty() {
+                            return Err(e);
+                        }
+                        self.abort_inflight();
+                        continue;
+                    }
+                    Some(libc::ETIME) | Some(libc::EINTR) | Some(libc::EBUSY) => {}
+                    _ => return Err(e.into()),
+                }
+            }
+
+            let cqes: Vec<_> = self
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            // All completions are processed even if one fails, so that the buffers
+            // and the number of outstanding operations are kept track of
+            let mut error = None;
+            for (user_data, result) in cqes {
+                self.outstanding -= 1;
+                if user_data & UringEngine::RECV != 0 {
+                    let r = self.on_recv((user_data & !UringEngine::RECV) as usize, result);
+                    error = error.or(r.err());
+                } else {
+                    self.on_send(user_data as usize, result);
+                }
+            }
+            if let Some(e) = error {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Submit `reqs` and wait for all of them
+    ///
+    /// Completions are returned in the order of completion.
+    pub fn run(
+        &mut self,
+        reqs: impl IntoIterator<Item = DmaRequest>,
+    ) -> Result<Vec<DmaCompletion>, Error> {
+        self.submit(reqs);
+        let mut completions = vec![];
+        while self.pending() > 0 {
+            completions.extend(self.complete()?);
+        }
+        completions.extend(std::mem::take(&mut self.completed));
+        Ok(completions)
+    }
+
+    fn alloc_req(&mut self, req: Request) -> usize {
+        match self.free_reqs.pop() {
+            Some(i) => {
+                self.reqs[i] = Some(req);
+                i
+            }
+            None => {
+                self.reqs.push(Some(req));
+                self.reqs.len() - 1
+            }
+        }
+    }
+
+    fn finish(&mut self, req: usize) {
+        if let Some(r) = self.reqs[req].take() {
+            self.free_reqs.push(req);
+            self.completed.push(DmaCompletion {
+                id: r.id,
+                result: match r.error {
+                    Some(e) => Err(e),
+                    None => Ok(r.data),
+                },
+            });
+        }
+    }
+
+    // Account `len` bytes of `req` as completed
+    fn progress(&mut self, req: usize, len: usize, error: Option<Error>) {
+        if let Some(r) = self.reqs[req].as_mut() {
+            r.remaining -= len;
+            if r.error.is_none() {
+                r.error = error;
+            }
+            if r.remaining == 0 {
+                self.finish(req);
+            }
+        }
+    }
+
+    // Fail the read requests in flight with `Error::Timeout`.
+    // Their tags are reused last so that their late completions are likely to be stale.
+    fn abort_inflight(&mut self) {
+        for m in std::mem::take(&mut self.inflight) {
+            self.free_tags.push_back(m.tag);
+            self.progress(m.req, m.len - m.received, Some(Error::Timeout));
+        }
+    }
+
+    // Remove the read request at `idx` from `inflight` and free its tag
+    fn retire(&mut self, idx: usize) -> InflightMrd {
+        let m = self.inflight.remove(idx);
+        self.free_tags.push_back(m.tag);
+        m
+    }
+
+    // Socket of `tag`
+    fn fd(&self, tag: u8) -> RawFd {
+        self.fds[tag.wrapping_sub(self.nettlp.tag) as usize]
+    }
+
+    // Queue send operations while a tag is free for read requests
+    fn fill(&mut self) -> Result<(), Error> {
+        while let Some(chunk) = self.queue.front() {
+            if matches!(chunk, Chunk::Read(_)) && self.free_tags.is_empty() {
+                break;
+            }
+            let sending = match self.queue.pop_front().unwrap() {
+                Chunk::Read(mut m) => {
+                    let tag = self.free_tags.pop_front().unwrap();
+                    let packet =
+                        self.nettlp
+                            .mr_packet(tag, m.addr, m.len, tlp::TlpType::Mrd, None)?;
+                    m.tag = tag;
+                    let req = m.req;
+                    self.inflight.push(m);
+                    Sending {
+                        req,
+                        tag,
+                        write_len: 0,
+                        packet,
+                    }
+                }
+                Chunk::Write {
+                    req,
+                    offset,
+                    addr,
+                    len,
+                } => {
+                    let data = &self.reqs[req].as_ref().unwrap().data[offset..offset + len];
+                    let tag = self.nettlp.tag;
+                    let packet =
+                        self.nettlp
+                            .mr_packet(tag, addr, len, tlp::TlpType::Mwr, Some(data))?;
+                    Sending {
+                        req,
+                        tag,
+                        write_len: len,
+                        packet,
+                    }
+                }
+            };
+
+            let slot = match self.sending.iter().position(|s| s.is_none()) {
+                Some(i) => i,
+                None => {
+                    self.sending.push(None);
+                    self.sending.len() - 1
+                }
+            };
+            let packet = &sending.packet;
+            let fd = types::Fd(self.fd(sending.tag));
+            let sqe = opcode::Send::new(fd, packet.as_ptr(), packet.len() as u32)
+                .build()
+                .user_data(slot as u64);
+            self.sending[slot] = Some(sending);
+            self.push(&sqe)?;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, sqe: &io_uring::squeue::Entry) -> Result<(), Error> {
+        // The queue is flushed when it is full
+        if self.ring.submission().is_full() {
+            self.ring.submit()?;
+        }
+        // The buffer of the operation lives until its completion is reaped
+        unsafe { self.ring.submission().push(sqe) }
+            .map_err(|_| Error::Io(std::io::ErrorKind::WouldBlock.into()))?;
+        self.outstanding += 1;
+        Ok(())
+    }
+
+    fn post_recv(&mut self, i: usize) -> Result<(), Error> {
+        let buf = &mut self.recv_bufs[i];
+        let sqe = opcode::Recv::new(types::Fd(self.fds[i]), buf.as_mut_ptr(), buf.len() as u32)
+            .build()
+            .user_data(UringEngine::RECV | i as u64);
+        self.push(&sqe)
+    }
+
+    fn on_send(&mut self, slot: usize, result: i32) {
+        let Some(sending) = self.sending.get_mut(slot).and_then(|s| s.take()) else {
+            return;
+        };
+        let error = if result < 0 {
+            Some(Error::from(std::io::Error::from_raw_os_error(-result)))
+        } else {
+            None
+        };
+        if sending.write_len > 0 {
+            // Memory writes are posted; they complete when sent
+            self.progress(sending.req, sending.write_len, error);
+        } else if let Some(e) = error {
+            // No completion will arrive for the read request
+            if let Some(i) = self.inflight.iter().position(|m| m.tag == sending.tag) {
+                let m = self.retire(i);
+                self.progress(m.req, m.len - m.received, Some(e));
+            }
+        }
+    }
+
+    fn on_recv(&mut self, i: usize, result: i32) -> Result<(), Error> {
+        if result < 0 {
+            if result == -libc::ECANCELED {
+                return Ok(());
+            }
+            let e = std::io::Error::from_raw_os_error(-result);
+            self.post_recv(i)?;
+            return Err(e.into());
+        }
+
+        let r = self.on_cpld(i, result as usize);
+        self.post_recv(i)?;
+        r
+    }
+
+    fn on_cpld(&mut self, i: usize, n: usize) -> Result<(), Error> {
+        let packet = &self.recv_bufs[i][..n];
+        let cpld = self.nettlp.parse_cpl(packet)?;
+        let Some(idx) = nettlp::find_inflight(&self.inflight, &cpld) else {
+            // Such as a late completion of a request that has timed out
+            self.nettlp.stale_cpl(&cpld);
+            return Ok(());
+        };
+        let m = &self.inflight[idx];
+        let addr = m.addr + m.received as u64;
+        if let Err(e) = self.nettlp.check_cpl(addr, &cpld, true) {
+            let m = self.retire(idx);
+            self.progress(m.req, m.len - m.received, Some(e));
+            return Ok(());
+        }
+
+        let data = nettlp::cpld_data(packet, &cpld).map_err(|e| self.nettlp.stats.error(e))?;
+        let size = data.len();
+        if size > m.len - m.received {
+            return Err(self.nettlp.stats.error(Error::InvalidData(format!(
+                "TLP payload size is larger than the requested size: {} > {}",
+                size,
+                m.len - m.received
+            ))));
+        }
+        Counters::add(&self.nettlp.stats.bytes_in, size);
+        let start = m.offset + m.received;
+        let req = m.req;
+        if let Some(r) = self.reqs[req].as_mut() {
+            r.data[start..start + size].copy_from_slice(data);
+        }
+        let m = &mut self.inflight[idx];
+        m.received += size;
+        if m.received == m.len {
+            self.retire(idx);
+        }
+        self.progress(req, size, None);
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for UringEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UringEngine")
+            .field("nettlp", &self.nettlp)
+            .field("pending", &self.pending())
+            .field("inflight", &self.inflight.len())
+            .finish()
+    }
+}
+
+impl Drop for UringEngine {
+    fn drop(&mut self) {
+        // Wait until the kernel releases all buffers
+        let mut ok = true;
+        for i in 0..self.recv_bufs.len() {
+            let sqe = opcode::AsyncCancel::new(UringEngine::RECV | i as u64)
+                .build()
+                .user_data(UringEngine::CANCEL);
+            ok &= unsafe { self.ring.submission().push(&sqe) }.is_ok();
+        }
+        while ok && self.outstanding > 0 {
+            ok = self.ring.submit_and_wait(1).is_ok();
+            for cqe in self.ring.completion() {
+                if cqe.user_data() != UringEngine::CANCEL {
+                    self.outstanding -= 1;
+                }
+            }
+        }
+        if !ok {
+            // Buffers may still be used by the kernel
+            std::mem::forget(std::mem::take(&mut self.recv_bufs));
+            std::mem::forget(std::mem::take(&mut self.sending));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{cpl, respond, Adapter, Mrd, UdpAdapter};
+
+    #[test]
+    fn pipelined_requests() {
+        let local_addr = Ipv4Addr::new(127, 0, 0, 1);
+        let remote_addr = Ipv4Addr::new(127, 0, 0, 2);
+        let bdf = pci::Bdf::new(1, 0, 0);
+        let dir = DmaDirection::DmaIssuedByLibTLP;
+        let tags = 4..8;
+
+        // Pretend to be the adapter on the port of each tag: reply the lower byte
+        // of the address as data, and an unsupported request for 0x9000
+        let adapters: Vec<_> = tags
+            .clone()
+            .map(|tag| {
+                let adapter = UdpAdapter::bind(remote_addr, UdpTransport::port(tag, dir));
+                respond(adapter, move |tlp| match Mrd::parse(tlp) {
+                    Some(mrd) if mrd.addr == 0x9000 => vec![cpl(mrd.tag, 1, mrd.count)],
+                    Some(mrd) => vec![mrd.reply(|_| mrd.addr as u8)],
+                    None => vec![],
+                })
+            })
+            .collect();
+
+        let mut engine = UringEngine::new(bdf, local_addr, remote_addr, tags, 64, dir).unwrap();
+        let mut reqs: Vec<_> = (0..8u64)
+            .map(|i| DmaRequest::Read {
+                addr: 0x1000 + i * 0x10,
+                len: 16,
+            })
+            .collect();
+        reqs.push(DmaRequest::Write {
+            addr: 0x2000,
+            data: vec![0; 128],
+        });
+        reqs.push(DmaRequest::Read {
+            addr: 0x9000,
+            len: 4,
+        });
+        let mut completions = engine.run(reqs).unwrap();
+        completions.sort_by_key(|c| c.id);
+        assert_eq!(completions.len(), 10);
+        for (i, cpl) in completions[..8].iter().enumerate() {
+            assert_eq!(cpl.result.as_ref().unwrap(), &vec![(i * 0x10) as u8; 16]);
+        }
+        assert_eq!(completions[8].result.as_ref().unwrap(), &vec![0; 128]);
+        assert!(matches!(
+            completions[9].result,
+            Err(Error::CompletionStatus {
+                status: tlp::CplStatus::Unsupported,
+                ..
+            })
+        ));
+        drop(engine);
+        // 8 reads of 16 bytes, 2 writes of 64 bytes and a read
+        let requests: usize = adapters.into_iter().map(|th| th.join().unwrap()).sum();
+        assert_eq!(requests, 11);
+    }
+
+    #[test]
+    fn timeout() {
+        let local_addr = Ipv4Addr::new(127, 0, 0, 1);
+        let remote_addr = Ipv4Addr::new(127, 0, 0, 2);
+        let bdf = pci::Bdf::new(1, 0, 0);
+        let dir = DmaDirection::DmaIssuedByLibTLP;
+        let fill = |addr: u64| (addr >> 8) as u8;
+        let read = |addr| DmaRequest::Read { addr, len: 8 };
+
+        // Pretend to be the adapter of tag 12, which does not reply to 0xa000 in time
+        let mut adapter = UdpAdapter::bind(remote_addr, UdpTransport::port(12, dir));
+        let mut engine = UringEngine::new(bdf, local_addr, remote_addr, 12..14, 512, dir).unwrap();
+        let completions = engine.run([read(0xa000)]).unwrap();
+        assert!(matches!(completions[0].result, Err(Error::Timeout)));
+
+        // Reply to 0xa000 late, then reply the second byte of the address as data
+        // on both tags
+        let mut buf = [0u8; 64];
+        let n = adapter.recv_request(&mut buf).unwrap();
+        let mrd = Mrd::parse(&buf[6..n]).unwrap();
+        assert_eq!(mrd.tag, 12);
+        adapter.send_reply(&mrd.reply(fill));
+        let adapters = [
+            adapter,
+            UdpAdapter::bind(remote_addr, UdpTransport::port(13, dir)),
+        ]
+        .map(|adapter| {
+            respond(adapter, move |tlp| {
+                vec![Mrd::parse(tlp).unwrap().reply(fill)]
+            })
+        });
+
+        // The tag of 0xa000 is reused last, and its late completion is dropped
+        let mut completions = engine.run([read(0xb000), read(0xc010)]).unwrap();
+        completions.sort_by_key(|c| c.id);
+        assert_eq!(completions[0].result.as_ref().unwrap(), &vec![0xb0; 8]);
+        assert_eq!(completions[1].result.as_ref().unwrap(), &vec![0xc0; 8]);
+        let stats = engine.nettlp().stats();
+        assert_eq!(stats.timeouts, 1);
+        assert_eq!(stats.stale, 1);
+        drop(engine);
+        let requests: usize = adapters.into_iter().map(|th| th.join().unwrap()).sum();
+        assert_eq!(requests, 2);
+    }
+}
@@ -0,0 +1,735 @@
+// AF_XDP transport
+//
+// Frames from the adapter are redirected by a small XDP program to an AF_XDP socket,
+// which places them in a memory area (UMEM) shared with the kernel.
+// Completion payloads are copied from the UMEM to the caller's buffer only once.
+// References
+//   - https://www.kernel.org/doc/html/latest/networking/af_xdp.html
+//   - https://www.kernel.org/doc/html/latest/bpf/instruction-set.html
+
+use crate::error::Error;
+use crate::ether::{self, UdpFlow};
+use crate::nettlp::DmaDirection;
+use crate::transport::{split_copy, Transport, UdpTransport};
+
+use bytes::buf::UninitSlice;
+
+use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// bpf(2) definitions (linux/bpf.h)
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_LINK_CREATE: libc::c_int = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+
+#[repr(C)]
+struct BpfMapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+struct BpfMapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+struct BpfLinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+// struct bpf_insn
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BpfInsn {
+    code: u8,
+    /// dst_reg (lower 4 bits) and src_reg (upper 4 bits)
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl BpfInsn {
+    const fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        BpfInsn {
+            code,
+            regs: dst | (src << 4),
+            off,
+            imm,
+        }
+    }
+}
+
+// Opcodes used by the XDP program
+const BPF_MOV64_X: u8 = 0xbf;
+const BPF_MOV64_K: u8 = 0xb7;
+const BPF_ADD64_K: u8 = 0x07;
+const BPF_LDX_W: u8 = 0x61;
+const BPF_LDX_H: u8 = 0x69;
+const BPF_LDX_B: u8 = 0x71;
+const BPF_LD_DW: u8 = 0x18;
+const BPF_JGT_X: u8 = 0x2d;
+const BPF_JNE_K: u8 = 0x55;
+const BPF_CALL: u8 = 0x85;
+const BPF_EXIT: u8 = 0x95;
+
+/// AF_XDP transport to the NetTLP adapter
+///
+/// Like `PacketTransport`, Ethernet/IPv4/UDP headers are crafted by this transport.
+/// An XDP program attached to the interface redirects UDP datagrams to the port of this
+/// transport to an AF_XDP socket bound to queue `queue_id`.
+/// Other packets are passed to the network stack.
+///
+/// The kernel uses zero-copy mode when the driver supports it and copy mode otherwise
+/// (e.g., on veth). Either way, a completion payload is copied only once,
+/// from the UMEM to the buffer given to `NetTlp`.
+///
+/// Only one XDP program can be attached to an interface,
+/// so only one `XdpTransport` can be used for an interface at a time.
+/// The program is detached when the transport is dropped.
+#[derive(Debug)]
+pub struct XdpTransport {
+    pub ifname: String,
+    pub queue_id: u32,
+    pub local_mac: [u8; 6],
+    pub remote_mac: [u8; 6],
+    pub local_addr: Ipv4Addr,
+    pub remote_addr: Ipv4Addr,
+    pub port: u16,
+    ip_id: AtomicU16,
+    // The XDP program is detached when the link is closed
+    _link: OwnedFd,
+    _prog: OwnedFd,
+    _map: OwnedFd,
+    umem: Mutex<Umem>,
+    fd: OwnedFd,
+}
+
+// UMEM and rings of an AF_XDP socket
+#[derive(Debug)]
+struct Umem {
+    area: *mut u8,
+    fill: Ring<u64>,
+    comp: Ring<u64>,
+    rx: Ring<libc::xdp_desc>,
+    tx: Ring<libc::xdp_desc>,
+    /// Frames available for TX
+    tx_frames: Vec<u64>,
+}
+
+// The UMEM and the rings are only accessed under the Mutex
+unsafe impl Send for Umem {}
+
+impl Drop for Umem {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.area as *mut _, XdpTransport::UMEM_SIZE);
+        }
+    }
+}
+
+// A single-producer single-consumer ring shared with the kernel
+#[derive(Debug)]
+struct Ring<T> {
+    map: *mut u8,
+    map_len: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    flags: *const AtomicU32,
+    descs: *mut T,
+    size: u32,
+}
+
+impl<T: Copy> Ring<T> {
+    fn new(
+        fd: RawFd,
+        off: &libc::xdp_ring_offset,
+        size: u32,
+        pgoff: libc::off_t,
+    ) -> Result<Self, Error> {
+        let map_len = off.desc as usize + size as usize * std::mem::size_of::<T>();
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                pgoff,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let map = map as *mut u8;
+        unsafe {
+            Ok(Ring {
+                map,
+                map_len,
+                producer: map.add(off.producer as usize) as *const AtomicU32,
+                consumer: map.add(off.consumer as usize) as *const AtomicU32,
+                flags: map.add(off.flags as usize) as *const AtomicU32,
+                descs: map.add(off.desc as usize) as *mut T,
+                size,
+            })
+        }
+    }
+
+    // Produce an entry (for the fill and TX rings)
+    fn push(&mut self, v: T) -> bool {
+        let (producer, consumer) = unsafe { (&*self.producer, &*self.consumer) };
+        let prod = producer.load(Ordering::Relaxed);
+        if prod.wrapping_sub(consumer.load(Ordering::Acquire)) == self.size {
+            return false;
+        }
+        unsafe { self.descs.add((prod & (self.size - 1)) as usize).write(v) };
+        producer.store(prod.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    // Consume an entry (for the RX and completion rings)
+    fn pop(&mut self) -> Option<T> {
+        let (producer, consumer) = unsafe { (&*self.producer, &*self.consumer) };
+        let cons = consumer.load(Ordering::Relaxed);
+        if cons == producer.load(Ordering::Acquire) {
+            return None;
+        }
+        let v = unsafe { self.descs.add((cons & (self.size - 1)) as usize).read() };
+        consumer.store(cons.wrapping_add(1), Ordering::Release);
+        Some(v)
+    }
+
+    fn needs_wakeup(&self) -> bool {
+        unsafe { (*self.flags).load(Ordering::Relaxed) & libc::XDP_RING_NEED_WAKEUP != 0 }
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        if !self.map.is_null() {
+            unsafe {
+                libc::munmap(self.map as *mut _, self.map_len);
+            }
+        }
+    }
+}
+
+impl XdpTransport {
+    /// The size of a UMEM frame
+    const FRAME_SIZE: usize = 4096;
+    /// The number of entries of each ring
+    const RING_SIZE: u32 = 512;
+    /// The UMEM has frames for RX (fill ring) and for TX
+    const UMEM_SIZE: usize = XdpTransport::FRAME_SIZE * 2 * XdpTransport::RING_SIZE as usize;
+
+    /// Open an AF_XDP socket on queue `queue_id` of `ifname` for `tag`
+    ///
+    /// This requires CAP_NET_ADMIN and CAP_BPF (or CAP_SYS_ADMIN).
+    pub fn new(
+        ifname: &str,
+        queue_id: u32,
+        remote_mac: [u8; 6],
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        tag: u8,
+        dir: DmaDirection,
+    ) -> Result<Self, Error> {
+        let local_mac = ether::read_mac(ifname)?;
+        let ifindex = ether::ifindex(ifname)?;
+        let port = UdpTransport::port(tag, dir);
+
+        let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let umem = XdpTransport::setup_umem(fd.as_raw_fd())?;
+
+        let sxdp = libc::sockaddr_xdp {
+            sxdp_family: libc::AF_XDP as u16,
+            sxdp_flags: libc::XDP_USE_NEED_WAKEUP,
+            sxdp_ifindex: ifindex,
+            sxdp_queue_id: queue_id,
+            sxdp_shared_umem_fd: 0,
+        };
+        let r = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &sxdp as *const _ as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_xdp>() as libc::socklen_t,
+            )
+        };
+        if r < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let map = xsk_map(queue_id, fd.as_raw_fd())?;
+        let prog = load_prog(map.as_raw_fd(), port)?;
+        let attr = BpfLinkCreateAttr {
+            prog_fd: prog.as_raw_fd() as u32,
+            target_ifindex: ifindex,
+            attach_type: BPF_XDP,
+            flags: 0,
+        };
+        let link = bpf(BPF_LINK_CREATE, &attr)?;
+
+        Ok(XdpTransport {
+            ifname: ifname.to_string(),
+            queue_id,
+            local_mac,
+            remote_mac,
+            local_addr,
+            remote_addr,
+            port,
+            ip_id: AtomicU16::new(0),
+            _link: link,
+            _prog: prog,
+            _map: map,
+            umem: Mutex::new(umem),
+            fd,
+        })
+    }
+
+    // Register the UMEM and map the rings
+    fn setup_umem(fd: RawFd) -> Result<Umem, Error> {
+        let area = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                XdpTransport::UMEM_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if area == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        // Unmapped by Umem::drop() from here
+        let mut umem = Umem {
+            area: area as *mut u8,
+            fill: Ring::new_unmapped(),
+            comp: Ring::new_unmapped(),
+            rx: Ring::new_unmapped(),
+            tx: Ring::new_unmapped(),
+            tx_frames: Vec::new(),
+        };
+
+        let reg = libc::xdp_umem_reg_v1 {
+            addr: area as u64,
+            len: XdpTransport::UMEM_SIZE as u64,
+            chunk_size: XdpTransport::FRAME_SIZE as u32,
+            headroom: 0,
+        };
+        setsockopt(fd, libc::XDP_UMEM_REG, &reg)?;
+        let size = XdpTransport::RING_SIZE;
+        setsockopt(fd, libc::XDP_UMEM_FILL_RING, &size)?;
+        setsockopt(fd, libc::XDP_UMEM_COMPLETION_RING, &size)?;
+        setsockopt(fd, libc::XDP_RX_RING, &size)?;
+        setsockopt(fd, libc::XDP_TX_RING, &size)?;
+
+        let mut off: libc::xdp_mmap_offsets = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::xdp_mmap_offsets>() as libc::socklen_t;
+        let r = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_XDP,
+                libc::XDP_MMAP_OFFSETS,
+                &mut off as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if r < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        umem.fill = Ring::new(
+            fd,
+            &off.fr,
+            size,
+            libc::XDP_UMEM_PGOFF_FILL_RING as libc::off_t,
+        )?;
+        umem.comp = Ring::new(
+            fd,
+            &off.cr,
+            size,
+            libc::XDP_UMEM_PGOFF_COMPLETION_RING as libc::off_t,
+        )?;
+        umem.rx = Ring::new(fd, &off.rx, size, libc::XDP_PGOFF_RX_RING)?;
+        umem.tx = Ring::new(fd, &off.tx, size, libc::XDP_PGOFF_TX_RING)?;
+
+        // The first half of the frames are for RX and the rest are for TX
+        for i in 0..size as u64 {
+            umem.fill.push(i * XdpTransport::FRAME_SIZE as u64);
+            umem.tx_frames
+                .push((size as u64 + i) * XdpTransport::FRAME_SIZE as u64);
+        }
+        Ok(umem)
+    }
+
+    fn flow(&self) -> UdpFlow {
+        UdpFlow {
+            local_mac: self.local_mac,
+            remote_mac: self.remote_mac,
+            local_addr: self.local_addr,
+            remote_addr: self.remote_addr,
+            port: self.port,
+        }
+    }
+
+    // Wait for a datagram of this flow and pass its payload to `f`
+    fn recv_with(
+        &self,
+        timeout: Duration,
+        mut f: impl FnMut(&[u8]) -> usize,
+    ) -> Result<usize, Error> {
+        let deadline = Instant::now() + timeout;
+        let flow = self.flow();
+        loop {
+            {
+                let mut umem = self.umem.lock().unwrap();
+                while let Some(desc) = umem.rx.pop() {
+                    let frame = unsafe {
+                        std::slice::from_raw_parts(
+                            umem.area.add(desc.addr as usize),
+                            desc.len as usize,
+                        )
+                    };
+                    let received = flow.parse(frame).map(&mut f);
+                    // Give the frame back to the kernel.
+                    // The fill ring never overflows as it can hold all RX frames.
+                    let frame_addr = desc.addr & !(XdpTransport::FRAME_SIZE as u64 - 1);
+                    umem.fill.push(frame_addr);
+                    if let Some(n) = received {
+                        return Ok(n);
+                    }
+                }
+            }
+            // poll(2) also wakes up the driver to process the fill ring if necessary
+            ether::poll(self.fd.as_raw_fd(), deadline)?;
+        }
+    }
+
+    // Kick the kernel to transmit frames in the TX ring
+    fn kick(&self) -> Result<(), Error> {
+        let r = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                std::ptr::null(),
+                0,
+                libc::MSG_DONTWAIT,
+                std::ptr::null(),
+                0,
+            )
+        };
+        if r < 0 {
+            let e = std::io::Error::last_os_error();
+            // The kernel is busy with previous frames; they will be sent anyway
+            match e.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) => {}
+                _ => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> Ring<T> {
+    // A placeholder until the ring is mapped
+    fn new_unmapped() -> Self {
+        Ring {
+            map: std::ptr::null_mut(),
+            map_len: 0,
+            producer: std::ptr::null(),
+            consumer: std::ptr::null(),
+            flags: std::ptr::null(),
+            descs: std::ptr::null_mut(),
+            size: 0,
+        }
+    }
+}
+
+impl Umem {
+    // Collect TX frames whose transmission has been completed
+    fn reclaim(&mut self) {
+        while let Some(addr) = self.comp.pop() {
+            self.tx_frames.push(addr);
+        }
+    }
+}
+
+impl Transport for XdpTransport {
+    fn send(&self, packet: &[u8]) -> Result<(), Error> {
+        let len = ether::HDR_SIZE + packet.len();
+        if len > XdpTransport::FRAME_SIZE {
+            return Err(Error::InvalidData(format!(
+                "Packet is larger than the UMEM frame: {} > {}",
+                len,
+                XdpTransport::FRAME_SIZE
+            )));
+        }
+        let mut umem = self.umem.lock().unwrap();
+        umem.reclaim();
+        let addr = match umem.tx_frames.pop() {
+            Some(addr) => addr,
+            None => {
+                self.kick()?;
+                umem.reclaim();
+                umem.tx_frames
+                    .pop()
+                    .ok_or_else(|| Error::Io(std::io::ErrorKind::WouldBlock.into()))?
+            }
+        };
+
+        let id = self.ip_id.fetch_add(1, Ordering::Relaxed);
+        let frame = unsafe { std::slice::from_raw_parts_mut(umem.area.add(addr as usize), len) };
+        frame[..ether::HDR_SIZE].copy_from_slice(&self.flow().headers(id, packet.len()));
+        frame[ether::HDR_SIZE..].copy_from_slice(packet);
+        // The TX ring never overflows as it can hold all TX frames
+        umem.tx.push(libc::xdp_desc {
+            addr,
+            len: len as u32,
+            options: 0,
+        });
+        if umem.tx.needs_wakeup() {
+            self.kick()?;
+        }
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        self.recv_with(timeout, |payload| {
+            let n = std::cmp::min(payload.len(), buf.len());
+            buf[..n].copy_from_slice(&payload[..n]);
+            n
+        })
+    }
+
+    // The payload is copied from the UMEM frame without an intermediate buffer
+    fn recv_split(
+        &self,
+        hdr: &mut [u8],
+        payload: &mut UninitSlice,
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        self.recv_with(timeout, |datagram| split_copy(datagram, hdr, payload))
+    }
+
+    fn discard_pending(&self) {
+        while self.recv_with(Duration::from_millis(0), |_| 0).is_ok() {}
+    }
+}
+
+fn setsockopt<T>(fd: RawFd, opt: libc::c_int, val: &T) -> Result<(), Error> {
+    let r = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_XDP,
+            opt,
+            val as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &T) -> Result<OwnedFd, Error> {
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *const T,
+            std::mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+// Create an XSKMAP that holds the socket `xsk` at `queue_id`
+fn xsk_map(queue_id: u32, xsk: RawFd) -> Result<OwnedFd, Error> {
+    let attr = BpfMapCreateAttr {
+        map_type: BPF_MAP_TYPE_XSKMAP,
+        key_size: 4,
+        value_size: 4,
+        max_entries: queue_id + 1,
+        map_flags: 0,
+    };
+    let map = bpf(BPF_MAP_CREATE, &attr)?;
+    let value = xsk as u32;
+    let attr = BpfMapElemAttr {
+        map_fd: map.as_raw_fd() as u32,
+        _pad: 0,
+        key: &queue_id as *const u32 as u64,
+        value: &value as *const u32 as u64,
+        flags: 0,
+    };
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_MAP_UPDATE_ELEM,
+            &attr as *const BpfMapElemAttr,
+            std::mem::size_of::<BpfMapElemAttr>() as libc::c_uint,
+        )
+    };
+    if r < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(map)
+}
+
+// Load an XDP program that redirects IPv4 UDP datagrams (without IP options)
+// to `port` to the XSKMAP `map`
+fn load_prog(map: RawFd, port: u16) -> Result<OwnedFd, Error> {
+    // Offsets in a frame
+    const ETHERTYPE: i16 = 12;
+    const IP_VER_IHL: i16 = 14;
+    const IP_PROTO: i16 = 23;
+    const UDP_DPORT: i16 = 36;
+    // Index of the instruction that passes the packet to the network stack
+    const PASS: i16 = 19;
+    let jne = |i: i16, imm: i32| BpfInsn::new(BPF_JNE_K, 4, 0, PASS - i - 1, imm);
+
+    let insns = [
+        // r2 = ctx->data, r3 = ctx->data_end
+        BpfInsn::new(BPF_LDX_W, 2, 1, 0, 0),
+        BpfInsn::new(BPF_LDX_W, 3, 1, 4, 0),
+        // if (r2 + 42 > r3) goto pass
+        BpfInsn::new(BPF_MOV64_X, 4, 2, 0, 0),
+        BpfInsn::new(BPF_ADD64_K, 4, 0, 0, ether::HDR_SIZE as i32),
+        BpfInsn::new(BPF_JGT_X, 4, 3, PASS - 4 - 1, 0),
+        // Fields in network byte order are loaded as is
+        BpfInsn::new(BPF_LDX_H, 4, 2, ETHERTYPE, 0),
+        jne(6, u16::from_ne_bytes(ether::ETH_P_IP.to_be_bytes()) as i32),
+        BpfInsn::new(BPF_LDX_B, 4, 2, IP_VER_IHL, 0),
+        jne(8, 0x45),
+        BpfInsn::new(BPF_LDX_B, 4, 2, IP_PROTO, 0),
+        jne(10, 17),
+        BpfInsn::new(BPF_LDX_H, 4, 2, UDP_DPORT, 0),
+        jne(12, u16::from_ne_bytes(port.to_be_bytes()) as i32),
+        // return bpf_redirect_map(map, ctx->rx_queue_index, XDP_PASS)
+        BpfInsn::new(BPF_LDX_W, 2, 1, 16, 0),
+        BpfInsn::new(BPF_LD_DW, 1, BPF_PSEUDO_MAP_FD, 0, map),
+        BpfInsn::new(0, 0, 0, 0, 0),
+        BpfInsn::new(BPF_MOV64_K, 3, 0, 0, XDP_PASS),
+        BpfInsn::new(BPF_CALL, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+        BpfInsn::new(BPF_EXIT, 0, 0, 0, 0),
+        // pass: return XDP_PASS
+        BpfInsn::new(BPF_MOV64_K, 0, 0, 0, XDP_PASS),
+        BpfInsn::new(BPF_EXIT, 0, 0, 0, 0),
+    ];
+
+    let license = b"Dual MIT/GPL\0";
+    let mut log = vec![0u8; 4096];
+    let mut name = [0u8; 16];
+    name[..6].copy_from_slice(b"libtlp");
+    let mut attr = BpfProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_XDP,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 0,
+        log_size: 0,
+        log_buf: 0,
+        kern_version: 0,
+        prog_flags: 0,
+        prog_name: name,
+        prog_ifindex: 0,
+        expected_attach_type: BPF_XDP,
+    };
+    match bpf(BPF_PROG_LOAD, &attr) {
+        Ok(fd) => Ok(fd),
+        Err(Error::Io(e)) if e.raw_os_error() == Some(libc::EACCES) => {
+            // Load again to get the verifier log
+            attr.log_level = 1;
+            attr.log_size = log.len() as u32;
+            attr.log_buf = log.as_mut_ptr() as u64;
+            let _ = bpf(BPF_PROG_LOAD, &attr);
+            let end = log.iter().position(|&b| b == 0).unwrap_or(log.len());
+            Err(Error::InvalidData(format!(
+                "XDP program is rejected: {}",
+                String::from_utf8_lossy(&log[..end])
+            )))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nettlp::NetTlp;
+    use crate::pci;
+    use crate::testutil::{respond, Mrd, UdpAdapter, Veth};
+
+    #[test]
+    #[ignore = "requires root to create a veth pair"]
+    fn veth() {
+        let (name, peer) = ("tlpveth4", "tlpveth5");
+        let local_addr = Ipv4Addr::new(192, 168, 252, 2);
+        let remote_addr = Ipv4Addr::new(192, 168, 252, 1);
+        let _veth = Veth::new(name, peer, local_addr, remote_addr);
+
+        let bdf = pci::Bdf::new(1, 0, 0);
+        let dir = DmaDirection::DmaIssuedByLibTLP;
+        let tag = 0;
+
+        // Pretend to be the adapter: reply 7 bytes from 0x1003 in 3 DWs
+        let adapter = UdpAdapter::bind(remote_addr, UdpTransport::port(tag, dir));
+        let th = respond(adapter, move |tlp| {
+            let mrd = Mrd::parse(tlp).unwrap();
+            vec![mrd.reply(|addr| addr.wrapping_sub(0x1002) as u8)]
+        });
+
+        let remote_mac = ether::read_mac(peer).unwrap();
+        let transport =
+            XdpTransport::new(name, 0, remote_mac, local_addr, remote_addr, tag, dir).unwrap();
+        let nettlp = NetTlp::with_transport(bdf, tag, 512, transport);
+        for _ in 0..2 {
+            let mut buf = vec![];
+            nettlp.dma_read(0x1003, &mut buf, 7).unwrap();
+            assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7]);
+        }
+        assert_eq!(th.join().unwrap(), 2);
+    }
+}